@@ -1,5 +1,13 @@
 fn main() {
     linker_be_nice();
+    // the linker arguments are for the ESP32 only, the host builds (`cargo test-host`, `cargo simulate`) use the default ones
+    if std::env::var("CARGO_CFG_TARGET_ARCH").as_deref() != Ok("riscv32") {
+        return;
+    }
+    println!(
+        "cargo:rustc-link-arg=--error-handling-script={}",
+        std::env::current_exe().unwrap().display()
+    );
     // make sure linkall.x is the last linker script (otherwise might cause problems with flip-link)
     println!("cargo:rustc-link-arg=-Tlinkall.x");
 }
@@ -48,9 +56,4 @@ fn linker_be_nice() {
 
         std::process::exit(0);
     }
-
-    println!(
-        "cargo:rustc-link-arg=--error-handling-script={}",
-        std::env::current_exe().unwrap().display()
-    );
 }
@@ -4,13 +4,18 @@
 use core::str::FromStr;
 use defmt::Debug2Format;
 use embassy_executor::Spawner;
-use embassy_futures::select::{Either, select};
-use embassy_net::{Runner, Stack, StackResources, tcp::TcpSocket};
+use embassy_futures::select::{Either, Either3, select, select3};
+use embassy_net::{
+    IpAddress, Runner, Stack, StackResources,
+    dns::DnsQueryType,
+    tcp::TcpSocket,
+    udp::{PacketMetadata, UdpSocket},
+};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, mutex::Mutex};
-use embassy_time::{Duration, Ticker, Timer};
+use embassy_time::{Duration, Instant, Ticker, Timer, with_deadline};
 use esp_alloc::HeapStats;
 use esp_hal::{
-    Blocking,
+    Async,
     clock::CpuClock,
     gpio::{self, Input},
     i2c::master::I2c,
@@ -24,20 +29,36 @@ use esp_radio::{
         ClientConfig, ModeConfig, ScanConfig, WifiController, WifiDevice, WifiEvent, WifiStaState,
     },
 };
-use heapless::{String, Vec};
 use next_tramway_esp32::{
-    display::{TramDisplay, TramNextPassage, UiCommand, UiState, apply_ui_command},
-    lcd::{Lcd, LcdRenderer},
+    diagnostics::{self, Diagnostics, Warning},
+    display::{Message, Rotation, Severity, Staleness, StateOverflow, TramDisplay, UiCommand, UiState, apply_ui_command},
+    input::{Gesture, GestureRecognizer, GestureTimings},
+    protocol::{self, Field, ParseError},
+    sntp::{self, SntpError},
 };
+#[cfg(feature = "encoder")]
+use next_tramway_esp32::input::{QuadratureDecoder, Turn};
+#[cfg(not(feature = "epaper"))]
+use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
+#[cfg(not(any(feature = "oled", feature = "epaper")))]
+use next_tramway_esp32::lcd::{Lcd, LcdGeometry, LcdRenderer, LcdRom};
+#[cfg(feature = "oled")]
+use next_tramway_esp32::oled::{Oled, OledController, OledRenderer};
+#[cfg(feature = "epaper")]
+use next_tramway_esp32::epaper::{Epaper, EpaperFramebuffer, EpaperRenderer};
+
+#[cfg(all(feature = "oled", feature = "epaper"))]
+compile_error!("the `oled` and `epaper` features are mutually exclusive");
 use rust_mqtt::{
     buffer::AllocBuffer,
     client::{
         Client,
         event::Event,
-        options::{ConnectOptions, SubscriptionOptions},
+        options::{ConnectOptions, PublicationOptions, SubscriptionOptions},
     },
     config::{KeepAlive, SessionExpiryInterval},
-    types::{MqttBinary, MqttString, TopicName},
+    types::{MqttBinary, MqttString, QoS, TopicName},
+    Bytes,
 };
 use static_cell::StaticCell;
 
@@ -51,14 +72,49 @@ macro_rules! mk_static {
     }};
 }
 
-fn str_to_msg(s: &str) -> heapless::String<80> {
+// cut if it's too long
+fn str_to_msg(s: &str) -> Message {
     let mut msg = heapless::String::new();
-    let _ = msg.push_str(s);
+    for c in s.chars() {
+        if msg.push(c).is_err() {
+            break;
+        }
+    }
     msg
 }
 
+// cleared once connected again
+const MQTT_CONNECT_ERROR: &str = "Failed to connect to MQTT server !";
+const MQTT_LOST_ERROR: &str = "MQTT connection lost";
+
+// message shown until it's replaced by a message at least as severe, or cleared
+fn status(severity: Severity, text: &str) -> UiCommand<MAX_PASSAGES> {
+    UiCommand::UpdateMessage { text: str_to_msg(text), severity, expires_after: None }
+}
+
 // Load env variables from .env file at compile time
+#[cfg(not(any(feature = "oled", feature = "epaper")))]
 const LCD_ADDR: u8 = 0x27;
+#[cfg(not(any(feature = "oled", feature = "epaper")))]
+const LCD_GEOMETRY: LcdGeometry = LcdGeometry::L2004; // use LcdGeometry::L1602 for the 16x2 modules
+#[cfg(not(any(feature = "oled", feature = "epaper")))]
+const LCD_ROM: LcdRom = LcdRom::A00; // use LcdRom::A02 if the controller is a HD44780UA02 (european characters)
+#[cfg(feature = "oled")]
+const OLED_ADDR: u8 = 0x3C;
+#[cfg(feature = "oled")]
+const OLED_CONTROLLER: OledController = OledController::Ssd1306; // use OledController::Sh1106 for the 1.3" modules
+
+// the display used is selected at compile time with the `oled` and `epaper` features
+#[cfg(not(any(feature = "oled", feature = "epaper")))]
+type Display = LcdRenderer<I2cDevice<'static, CriticalSectionRawMutex, I2c<'static, Async>>>;
+#[cfg(feature = "oled")]
+type Display = OledRenderer<I2cDevice<'static, CriticalSectionRawMutex, I2c<'static, Async>>>;
+#[cfg(feature = "epaper")]
+type Display = EpaperRenderer<'static>;
+
+// number of partial refreshes of the e-paper between two full refreshes (which flash the screen but clear the ghosting)
+#[cfg(feature = "epaper")]
+const EPAPER_FULL_REFRESH_EVERY: u8 = 30;
 
 const SSID: &str = env!("SSID");
 const PASSWORD: &str = env!("PASSWORD");
@@ -66,6 +122,55 @@ const PASSWORD: &str = env!("PASSWORD");
 const KEEP_ALIVE_SECS: u16 = 12;
 const SOCKET_TIMEOUT_SECS: u64 = 30;
 
+// how often the display is refreshed without any new command (countdown of the arrival minutes)
+// the LCD is refreshed faster to scroll the long destinations, the diffing keeps it cheap
+#[cfg(not(any(feature = "oled", feature = "epaper")))]
+const RENDER_TICK: Duration = Duration::from_millis(next_tramway_esp32::lcd::MARQUEE_STEP_MS);
+#[cfg(any(feature = "oled", feature = "epaper"))]
+const RENDER_TICK: Duration = Duration::from_secs(5);
+
+// the Home Assistant script publishes every 20 seconds, after these delays the data is flagged as old / dropped
+const STALE_AFTER_SECS: u64 = 2 * 60;
+const NO_DATA_AFTER_SECS: u64 = 10 * 60;
+
+// short press: next screen, long press: backlight, double click: previous screen (see `button_command`)
+// a short press is only sent once the double click window is over
+const GESTURE_TIMINGS: GestureTimings = GestureTimings {
+    debounce: Duration::from_millis(30),
+    long_press: Duration::from_millis(800),
+    double_click: Duration::from_millis(300),
+};
+
+// the KY-040 does a whole quadrature cycle between two detents (see `QuadratureDecoder`)
+#[cfg(feature = "encoder")]
+const ENCODER_TRANSITIONS_PER_DETENT: u8 = 4;
+
+// automatic cycling through the screens, each screen stays ROTATION_DWELL_SECS
+// after a button press the rotation is paused for ROTATION_PAUSE_AFTER_PRESS_SECS
+const AUTO_ROTATION: bool = false;
+const ROTATION_DWELL_SECS: u64 = 10;
+const ROTATION_PAUSE_AFTER_PRESS_SECS: u64 = 60;
+// the clock is one more screen, after the last direction (the rotation and the button go through it)
+const CLOCK_IN_ROTATION: bool = false;
+
+// capacities of the UI state, raise them for a stop with more lines or a display showing more passages
+// the whole state is allocated up front: about 40 bytes per passage, 50 per direction and 40 per line
+const MAX_LINES: usize = 8;
+const MAX_DIRECTIONS: usize = 2;
+const MAX_PASSAGES: usize = 3;
+// commands waiting for the renderer, the MQTT task and the buttons wait when it's full
+const UI_CHANNEL_DEPTH: usize = 8;
+
+// payloads that don't fit and lines dropped for lack of room are logged and counted in DIAGNOSTICS,
+// and also published on `next-tramway/status/warning` so the backend author can see them
+const PUBLISH_WARNINGS: bool = true;
+
+// how long the "wifi connection failed" warning stays, the next attempt shows it again if it's still down
+const WIFI_WARNING_SECS: u64 = 30;
+
+// order of the screens: these line ids first, e.g. &["C", "A"], then the other lines sorted by id
+const LINE_ORDER: &[&str] = &[];
+
 const MQTT_HOST: &str = env!("MQTT_HOST");
 const MQTT_PORT: &str = env!("MQTT_PORT");
 const MQTT_USERNAME: &str = env!("MQTT_USERNAME");
@@ -73,6 +178,17 @@ const MQTT_PASSWORD: &str = env!("MQTT_PASSWORD");
 
 const MQTT_CLIENT_ID: &str = env!("MQTT_CLIENT_ID");
 
+// time server, an IP address or a host name, e.g. the router or a local chrony to test without internet
+// the clock gives the absolute arrivals and the true age of the data (see `UiCommand::SetTime`)
+const SNTP_SERVER: &str = match option_env!("SNTP_SERVER") {
+    Some(server) if !server.is_empty() => server,
+    _ => "pool.ntp.org",
+};
+// the clock of the ESP32 drifts by a few seconds a day, once an hour is plenty
+const SNTP_RESYNC_SECS: u64 = 60 * 60;
+const SNTP_RETRY_SECS: u64 = 30;
+const SNTP_TIMEOUT_SECS: u64 = 5;
+
 //---------------------------------------------------
 
 #[cfg(feature = "debug")]
@@ -87,10 +203,13 @@ static TX_BUF: StaticCell<[u8; 4096]> = StaticCell::new();
 
 esp_bootloader_esp_idf::esp_app_desc!();
 
-static I2C_BUS: Mutex<CriticalSectionRawMutex, Option<I2c<'static, Blocking>>> = Mutex::new(None);
-
 // send ui command bewteen tasks
-static UI_CH: Channel<CriticalSectionRawMutex, UiCommand, 8> = Channel::new();
+static UI_CH: Channel<CriticalSectionRawMutex, UiCommand<MAX_PASSAGES>, UI_CHANNEL_DEPTH> = Channel::new();
+
+// shared by the MQTT task and the renderer
+static DIAGNOSTICS: Diagnostics = Diagnostics::new();
+// warnings waiting to be published by the MQTT task, dropped if it's full (e.g. while disconnected)
+static WARNING_CH: Channel<CriticalSectionRawMutex, Warning, 4> = Channel::new();
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
@@ -110,14 +229,13 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
 }
 
 // Used to diagnose i2c issues
-async fn scan_i2c_bus() {
+async fn scan_i2c_bus(i2c_bus: &Mutex<CriticalSectionRawMutex, I2c<'static, Async>>) {
     esp_println::println!("Scanning I2C bus...");
 
     for addr in 0x08..=0x77 {
-        let mut guard = I2C_BUS.lock().await;
-        let i2c = guard.as_mut().expect("I2C not initialized");
+        let mut i2c = i2c_bus.lock().await;
         esp_println::print!("0x{:02X}...", addr);
-        if i2c.write(addr, &[]).is_ok() {
+        if i2c.write_async(addr, &[]).await.is_ok() {
             esp_println::println!("I2C device found at 0x{:02X}", addr);
         }
     }
@@ -134,6 +252,21 @@ async fn main(spawner: Spawner) {
     let i2c_scl = peripherals.GPIO7; // GPIO used for I2C SCL, connected to the LCD
     let i2c_sda = peripherals.GPIO6; // GPIO used for I2C SDA, connected to the LCD
     let button_gpio = peripherals.GPIO4; // GPIO used for the button input, connected to a push button with a pull-up resistor (so the input is low when the button is pressed)
+    #[cfg(feature = "encoder")]
+    let (encoder_clk, encoder_dt, encoder_sw) = (
+        peripherals.GPIO2, // CLK (A output) of the rotary encoder
+        peripherals.GPIO3, // DT (B output) of the rotary encoder
+        peripherals.GPIO5, // SW, the push switch of the encoder shaft, active low like the button
+    );
+    #[cfg(feature = "epaper")]
+    let (epaper_sck, epaper_mosi, epaper_cs, epaper_dc, epaper_rst, epaper_busy) = (
+        peripherals.GPIO19, // SPI clock
+        peripherals.GPIO18, // SPI data (DIN on the e-paper module)
+        peripherals.GPIO20, // SPI chip select
+        peripherals.GPIO21, // data/command selection
+        peripherals.GPIO22, // reset
+        peripherals.GPIO23, // busy, high while the panel refreshes
+    );
     //------------------------------
 
     // max reclaimable ram (on ESP32-C6)
@@ -157,40 +290,40 @@ async fn main(spawner: Spawner) {
     esp_println::println!("Embassy init !");
 
     // I2C setup
-
-    let i2c_bus = esp_hal::i2c::master::I2c::new(
-        peripherals.I2C0,
-        esp_hal::i2c::master::Config::default().with_frequency(Rate::from_khz(400)),
-    )
-    .unwrap()
-    .with_scl(i2c_scl)
-    .with_sda(i2c_sda);
-
-    I2C_BUS.lock().await.replace(i2c_bus);
+    // the bus is behind a mutex so it can be shared between the display and the scan
+    let i2c_bus = &*mk_static!(
+        Mutex<CriticalSectionRawMutex, I2c<'static, Async>>,
+        Mutex::new(
+            esp_hal::i2c::master::I2c::new(
+                peripherals.I2C0,
+                esp_hal::i2c::master::Config::default().with_frequency(Rate::from_khz(400)),
+            )
+            .unwrap()
+            .with_scl(i2c_scl)
+            .with_sda(i2c_sda)
+            .into_async()
+        )
+    );
     esp_println::println!("I2C Bus init !");
     UI_CH
-        .send(UiCommand::UpdateMessage(str_to_msg("I2C Bus initialized")))
+        .send(status(Severity::Info, "I2C Bus initialized"))
         .await;
     if DEBUG {
-        scan_i2c_bus().await;
+        scan_i2c_bus(i2c_bus).await;
     }
 
     // Radio setup
     let esp_radio_ctrl = &*mk_static!(Controller<'static>, esp_radio::init().unwrap());
     esp_println::println!("radio controlller init !");
     UI_CH
-        .send(UiCommand::UpdateMessage(str_to_msg(
-            "radio controlller init !",
-        )))
+        .send(status(Severity::Info, "radio controlller init !"))
         .await;
 
     let (controller, interfaces) =
         esp_radio::wifi::new(esp_radio_ctrl, peripherals.WIFI, Default::default()).unwrap();
     esp_println::println!("Wifi controlller init !");
     UI_CH
-        .send(UiCommand::UpdateMessage(str_to_msg(
-            "Wifi controlller init !",
-        )))
+        .send(status(Severity::Info, "Wifi controlller init !"))
         .await;
 
     let wifi_interface = interfaces.sta;
@@ -211,15 +344,48 @@ async fn main(spawner: Spawner) {
     spawner.spawn(connection(controller)).ok();
     spawner.spawn(net_task(runner)).ok();
     spawner.spawn(mqtt(stack)).ok();
+    spawner.spawn(sntp_task(stack)).ok();
 
     // Renderer setup
-    let mut lcd = Lcd::new(
-        &I2C_BUS,
-        LCD_ADDR,
-        next_tramway_esp32::lcd::LcdGeometry::L2004,
-    );
-    lcd.init().await;
-    spawner.spawn(renderer(LcdRenderer::new(lcd))).ok();
+    #[cfg(not(any(feature = "oled", feature = "epaper")))]
+    let display = {
+        let mut lcd = Lcd::new(I2cDevice::new(i2c_bus), LCD_ADDR, LCD_GEOMETRY, LCD_ROM);
+        lcd.init().await;
+        LcdRenderer::new(lcd)
+    };
+    #[cfg(feature = "oled")]
+    let display = {
+        let mut oled = Oled::new(I2cDevice::new(i2c_bus), OLED_ADDR, OLED_CONTROLLER);
+        oled.init().await;
+        OledRenderer::new(oled)
+    };
+    #[cfg(feature = "epaper")]
+    let display = {
+        let spi = esp_hal::spi::master::Spi::new(
+            peripherals.SPI2,
+            esp_hal::spi::master::Config::default()
+                .with_frequency(Rate::from_mhz(4))
+                .with_mode(esp_hal::spi::Mode::_0),
+        )
+        .unwrap()
+        .with_sck(epaper_sck)
+        .with_mosi(epaper_mosi)
+        .with_cs(epaper_cs);
+        let epaper = Epaper::new(
+            spi,
+            gpio::Output::new(epaper_dc, gpio::Level::Low, gpio::OutputConfig::default()),
+            gpio::Output::new(epaper_rst, gpio::Level::High, gpio::OutputConfig::default()),
+            Input::new(epaper_busy, gpio::InputConfig::default()),
+        );
+        // the framebuffers are too big to be moved around in the renderer task
+        EpaperRenderer::new(
+            epaper,
+            mk_static!(EpaperFramebuffer, EpaperFramebuffer::new()),
+            mk_static!(EpaperFramebuffer, EpaperFramebuffer::new()),
+            EPAPER_FULL_REFRESH_EVERY,
+        )
+    };
+    spawner.spawn(renderer(display)).ok();
 
 
     // Button setup
@@ -227,7 +393,17 @@ async fn main(spawner: Spawner) {
         button_gpio,
         gpio::InputConfig::default().with_pull(gpio::Pull::Up),
     );
-    spawner.spawn(button_task(button)).ok();
+    spawner.spawn(button_task(button, button_command)).ok();
+
+    // Rotary encoder setup, the KY-040 module has pull-ups on CLK and DT but not on SW
+    #[cfg(feature = "encoder")]
+    {
+        let pull_up = gpio::InputConfig::default().with_pull(gpio::Pull::Up);
+        let clk = Input::new(encoder_clk, pull_up);
+        let dt = Input::new(encoder_dt, pull_up);
+        spawner.spawn(encoder_task(clk, dt)).ok();
+        spawner.spawn(button_task(Input::new(encoder_sw, pull_up), encoder_switch_command)).ok();
+    }
 
     let stats: HeapStats = esp_alloc::HEAP.stats();
     esp_println::println!("{}", stats);
@@ -237,27 +413,43 @@ async fn main(spawner: Spawner) {
 
 // Renderer task, receives ui commands and updates the display accordingly
 #[embassy_executor::task]
-async fn renderer(mut display: LcdRenderer<'static>) {
-    let mut state = UiState {
-        lines: heapless::Vec::new(),
-        current_message: None,
-        current_line: 0,
-        current_direction_id: 0,
-        backlight_on: true,
+async fn renderer(mut display: Display) {
+    let mut state: UiState<MAX_LINES, MAX_DIRECTIONS, MAX_PASSAGES> = UiState::new(
+        Staleness {
+            stale_after: Duration::from_secs(STALE_AFTER_SECS),
+            no_data_after: Duration::from_secs(NO_DATA_AFTER_SECS),
+        },
+        Instant::now(),
+    );
+    // can also be changed at runtime with the `next-tramway/command/rotation` topic
+    state.rotation = Rotation {
+        enabled: AUTO_ROTATION,
+        dwell: Duration::from_secs(ROTATION_DWELL_SECS),
+        pause_after_press: Duration::from_secs(ROTATION_PAUSE_AFTER_PRESS_SECS),
     };
-    
-let mut ticker = Ticker::every(Duration::from_secs(10));
+    state.line_order = LINE_ORDER;
+    state.clock_in_rotation = CLOCK_IN_ROTATION;
+
+    let mut healthcheck_ticker = Ticker::every(Duration::from_secs(10));
+    // re-render periodically so the arrival minutes keep counting down between two MQTT updates
+    let mut render_ticker = Ticker::every(RENDER_TICK);
     esp_println::println!("Renderer ready !");
     loop {
         
-        match select(UI_CH.receive(), ticker.next()).await {
-            Either::First(cmd) => {
-                apply_ui_command(&mut state, cmd);
+        match select3(UI_CH.receive(), healthcheck_ticker.next(), render_ticker.next()).await {
+            Either3::First(cmd) => {
+                if let Some(overflow) = apply_ui_command(&mut state, cmd, Instant::now()) {
+                    report_state_overflow(&overflow);
+                }
                 display.render(&state).await;
             }
-            Either::Second(_) => {
+            Either3::Second(_) => {
                 display.healthcheck().await;
             }
+            Either3::Third(_) => {
+                apply_ui_command(&mut state, UiCommand::Tick, Instant::now());
+                display.render(&state).await;
+            }
         }
         // let cmd = UI_CH.receive().await;
     }
@@ -292,7 +484,7 @@ async fn connection(mut controller: WifiController<'static>) {
             if DEBUG {
                 esp_println::println!("Scan");
                 UI_CH
-                    .send(UiCommand::UpdateMessage(str_to_msg("Scanning wifi...")))
+                    .send(status(Severity::Info, "Scanning wifi..."))
                     .await;
                 let scan_config = ScanConfig::default().with_max(1).with_ssid(SSID);
                 let result = controller
@@ -306,7 +498,7 @@ async fn connection(mut controller: WifiController<'static>) {
         }
         esp_println::println!("About to connect...");
         UI_CH
-            .send(UiCommand::UpdateMessage(str_to_msg("About to connect...")))
+            .send(status(Severity::Info, "About to connect..."))
             .await;
 
         // wifi stack use a lot of memory, print heap stats before connecting to help diagnose OOM issues
@@ -317,11 +509,18 @@ async fn connection(mut controller: WifiController<'static>) {
             Ok(_) => {
                 esp_println::println!("Wifi connected!");
                 UI_CH
-                    .send(UiCommand::UpdateMessage(str_to_msg("Wifi connected !")))
+                    .send(status(Severity::Info, "Wifi connected !"))
                     .await;
             }
             Err(e) => {
                 esp_println::println!("Failed to connect to wifi: {e:?}");
+                UI_CH
+                    .send(UiCommand::UpdateMessage {
+                        text: str_to_msg("Wifi: connexion impossible"),
+                        severity: Severity::Warning,
+                        expires_after: Some(Duration::from_secs(WIFI_WARNING_SECS)),
+                    })
+                    .await;
                 Timer::after(Duration::from_millis(500)).await
             }
         }
@@ -337,9 +536,7 @@ async fn net_task(mut runner: Runner<'static, WifiDevice<'static>>) {
 async fn wait_for_ip(stack: Stack<'_>) {
     esp_println::println!("Waiting to get IP address...");
     UI_CH
-        .send(UiCommand::UpdateMessage(str_to_msg(
-            "Waiting to get IP address...",
-        )))
+        .send(status(Severity::Info, "Waiting to get IP address..."))
         .await;
     loop {
         if let Some(config) = stack.config_v4() {
@@ -405,10 +602,12 @@ async fn mqtt_connect<'a>(
     {
         Ok(c) => {
             esp_println::println!("Connected to server: {:?}", c);
+            // the error of the previous connection is over, the other messages (wifi, overflows...) stay
+            for error in [MQTT_LOST_ERROR, MQTT_CONNECT_ERROR] {
+                UI_CH.send(UiCommand::ClearMessage { text: str_to_msg(error) }).await;
+            }
             UI_CH
-                .send(UiCommand::UpdateMessage(str_to_msg(
-                    "Connected to MQTT server !",
-                )))
+                .send(status(Severity::Info, "Connected to MQTT server !"))
                 .await;
             esp_println::println!("{:?}", mqtt_client.client_config());
             esp_println::println!("{:?}", mqtt_client.server_config());
@@ -418,9 +617,7 @@ async fn mqtt_connect<'a>(
         Err(e) => {
             esp_println::println!("Failed to connect to server {:?}", e);
             UI_CH
-                .send(UiCommand::UpdateMessage(str_to_msg(
-                    "Failed to connect to MQTT server !",
-                )))
+                .send(status(Severity::Error, MQTT_CONNECT_ERROR))
                 .await;
             // could use an exponential backoff here
             Timer::after(Duration::from_secs(2)).await;
@@ -475,139 +672,202 @@ async fn mqtt(stack: embassy_net::Stack<'static>) {
         let mut ticker = Ticker::every(Duration::from_secs(KEEP_ALIVE_SECS as u64 / 2));
         // loop MQTT
         loop {
-            match select(mqtt_client.poll(), ticker.next()).await {
-                Either::First(res) => match res {
+            match select3(mqtt_client.poll(), ticker.next(), WARNING_CH.receive()).await {
+                Either3::First(res) => match res {
                     Ok(event) => handle_mqtt_event(event).await,
                     Err(e) => {
                         esp_println::println!("MQTT error: {:?}", e);
                         break;
                     }
                 },
-                Either::Second(_) => {
+                Either3::Second(_) => {
                     if mqtt_client.ping().await.is_err() {
                         esp_println::println!("Ping failed");
                         break;
                     }
                 }
+                Either3::Third(warning) => {
+                    let topic = unsafe { TopicName::new_unchecked(MqttString::from_slice(diagnostics::WARNING_TOPIC).unwrap()) };
+                    let options = PublicationOptions { retain: false, topic, qos: QoS::AtMostOnce };
+                    if let Err(e) = mqtt_client.publish(&options, Bytes::from(warning.as_str())).await {
+                        esp_println::println!("Failed to publish warning: {:?}", e);
+                        break;
+                    }
+                }
             }
         }
         esp_println::println!("Connection to MQTT server lost...");
+        // the passages stop being updated, don't let them look reliable
+        UI_CH.send(status(Severity::Error, MQTT_LOST_ERROR)).await;
         // could use an exponential backoff here
         Timer::after(Duration::from_secs(2)).await;
     }
 }
 
-async fn handle_mqtt_event(event: Event<'_>) {
-    let Event::Publish(p) = event else { return };
-    if let Ok(text) = core::str::from_utf8(p.message.as_ref()) {
-        if p.topic.as_ref().starts_with("next-tramway/line") {
-            if let Some(cmd) = parse_mqtt_event(&p.topic, text) {
-                UI_CH.send(cmd).await;
-            } else {
-                esp_println::println!("Failed to parse MQTT event: {:?}", p);
-            }
-        } else if p.topic.as_ref().starts_with("next-tramway/command") {
-            if p.topic.as_ref() == "next-tramway/command/backlight" {
-                match text {
-                    "on" => {
-                        esp_println::println!("Received set backlight on command");
-                        UI_CH.send(UiCommand::SetBacklight(true)).await;
-                    },
-                    "off" => {
-                        esp_println::println!("Received set backlight off command");
-                        UI_CH.send(UiCommand::SetBacklight(false)).await;
-                    },
-                    _ => {
-                        esp_println::println!("Received unknown command: {}", text);
-                    }
-                }
-            } else {
-                match text {
-                    "toggle_backlight" => {
-                        esp_println::println!("Received toggle backlight command");
-                        UI_CH.send(UiCommand::ToggleBacklight).await;
-                    },
-                    _ => {
-                        esp_println::println!("Received unknown command: {}", text);
-                    }
-                }
+// sets the clock of the UI at boot, then keeps it from drifting
+#[embassy_executor::task]
+async fn sntp_task(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0; 256];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0; 128];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+    // any free local port
+    socket.bind(0).expect("Couldn't bind the SNTP socket");
+    let rng = esp_hal::rng::Rng::new();
+
+    loop {
+        stack.wait_config_up().await;
+        let nonce = (rng.random() as u64) << 32 | rng.random() as u64;
+        match sync_clock(stack, &socket, nonce).await {
+            Some(unix_secs) => {
+                esp_println::println!("Clock set by {}: {}", SNTP_SERVER, unix_secs);
+                UI_CH.send(UiCommand::SetTime { unix_secs }).await;
+                Timer::after(Duration::from_secs(SNTP_RESYNC_SECS)).await;
             }
-        } else {
-            esp_println::println!("Unknown topic: {}", p.topic.as_ref());
-        } 
+            None => Timer::after(Duration::from_secs(SNTP_RETRY_SECS)).await,
+        }
     }
 }
 
-fn parse_mqtt_event(topic: &MqttString, text: &str) -> Option<UiCommand> {
-    let mut parts = topic.as_ref().rsplit('/');
-
-    if let (Some(direction_id), Some(_)) = (parts.next(), parts.next()) {
-        let mut next_passages: heapless::Vec<TramNextPassage, 3> = Vec::new();
-        let mut text_split_iter = text.split('\n');
-        let mut line: String<16> = heapless::String::new();
-        let _ = line.push_str(text_split_iter.next()?);
-        if let Some(update_at) = text_split_iter.next_back() {
-            for passage in text_split_iter {
-                let mut destination_buffer: String<32> = heapless::String::new();
-                let mut passage_parts = passage.split("|");
-                if let (Some(destination), Some(relative_arrival), Some(_)) = (
-                    passage_parts.next(),
-                    passage_parts.next(),
-                    passage_parts.next(),
-                ) {
-                    let _ = destination_buffer.push_str(destination);
-                    let _ = next_passages.push(TramNextPassage {
-                        destination: destination_buffer,
-                        relative_arrival: match relative_arrival.parse() {
-                            Ok(value) => value,
-                            Err(_) => {
-                                esp_println::println!(
-                                    "Failed to parse relative_arrival: {}",
-                                    relative_arrival
-                                );
-                                return None;
-                            }
-                        },
-                    });
-                }
+// one request to the SNTP server, returns the current UTC time if successful
+async fn sync_clock(stack: Stack<'_>, socket: &UdpSocket<'_>, nonce: u64) -> Option<u64> {
+    let address = match IpAddress::from_str(SNTP_SERVER) {
+        Ok(address) => address,
+        Err(_) => match stack.dns_query(SNTP_SERVER, DnsQueryType::A).await {
+            Ok(addresses) => *addresses.first()?,
+            Err(e) => {
+                esp_println::println!("Failed to resolve {}: {:?}", SNTP_SERVER, e);
+                return None;
             }
-            let mut update_at_buffer: String<10> = heapless::String::new();
-            let _ = update_at_buffer.push_str(update_at);
-
-            let direction_id = match direction_id.parse() {
-                Ok(id) => id,
-                Err(_) => {
-                    esp_println::println!("Failed to parse direction_id: {}", direction_id);
-                    return None;
-                }
-            };
-
-            let cmd = UiCommand::UpdateDirection {
-                line,
-                direction_id,
-                next_passages,
-                update_at: update_at_buffer,
-            };
+        },
+    };
+
+    let sent_at = Instant::now();
+    if let Err(e) = socket.send_to(&sntp::request(nonce), (address, sntp::NTP_PORT)).await {
+        esp_println::println!("Failed to send the SNTP request: {:?}", e);
+        return None;
+    }
+    let mut response = [0; 128];
+    // for the whole exchange: the stray responses skipped below don't give the server more time
+    let deadline = sent_at + Duration::from_secs(SNTP_TIMEOUT_SECS);
+    loop {
+        let (len, _) = match with_deadline(deadline, socket.recv_from(&mut response)).await {
+            Ok(Ok(received)) => received,
+            Ok(Err(e)) => {
+                esp_println::println!("Failed to receive the SNTP response: {:?}", e);
+                return None;
+            }
+            Err(_) => {
+                esp_println::println!("No response from {}", SNTP_SERVER);
+                return None;
+            }
+        };
+        match sntp::parse_response(&response[..len], nonce, Instant::now() - sent_at) {
+            Ok(unix_millis) => return Some(unix_millis / 1000),
+            // late response to a previous attempt, ours may still come
+            Err(SntpError::UnexpectedOriginate) => continue,
+            Err(e) => {
+                esp_println::println!("Unusable SNTP response: {:?}", e);
+                return None;
+            }
+        }
+    }
+}
+
+async fn handle_mqtt_event(event: Event<'_>) {
+    let Event::Publish(p) = event else { return };
+    match protocol::parse_payload(p.topic.as_ref(), p.message.as_ref()) {
+        Ok((cmd, overflow)) => {
             esp_println::println!("{:?}", cmd);
-            return Some(cmd);
+            UI_CH.send(cmd).await;
+            // what fits is shown all the same
+            if let Some(field) = overflow {
+                esp_println::println!("Truncated {:?} of the MQTT event on {}", field, p.topic.as_ref());
+                DIAGNOSTICS.record_overflow(field);
+                report_overflow(p.topic.as_ref(), field);
+            }
+        }
+        Err(e) => {
+            esp_println::println!("Failed to parse MQTT event on {}: {:?}", p.topic.as_ref(), e);
+            DIAGNOSTICS.record_parse_error(&e);
+            if let ParseError::Overflow(field) = e {
+                report_overflow(p.topic.as_ref(), field);
+            }
         }
     }
-    None
 }
 
-#[embassy_executor::task]
-async fn button_task(mut button: Input<'static>) {
-    loop {
-        button.wait_for_falling_edge().await;
+// already counted, tell the backend author
+fn report_overflow(topic: &str, field: Field) {
+    esp_println::println!("{}", DIAGNOSTICS);
+    if PUBLISH_WARNINGS {
+        let _ = WARNING_CH.try_send(diagnostics::overflow_warning::<MAX_PASSAGES>(topic, field));
+    }
+}
 
-        Timer::after(Duration::from_millis(50)).await;
+// the state is already updated, the renderer only reports what was dropped
+fn report_state_overflow(overflow: &StateOverflow) {
+    DIAGNOSTICS.record_state_overflow(overflow);
+    esp_println::println!("{}", DIAGNOSTICS);
+    if PUBLISH_WARNINGS {
+        let _ = WARNING_CH.try_send(diagnostics::state_overflow_warning::<MAX_LINES, MAX_DIRECTIONS>(overflow));
+    }
+}
 
-        if button.is_low() {
-            esp_println::println!("BOUTON");
-            UI_CH.send(UiCommand::NextScreen).await;
-        }
+// what the push button does
+fn button_command(gesture: Gesture) -> Option<UiCommand<MAX_PASSAGES>> {
+    match gesture {
+        Gesture::ShortPress => Some(UiCommand::NextScreen),
+        Gesture::LongPress => Some(UiCommand::ToggleBacklight),
+        Gesture::DoubleClick => Some(UiCommand::PreviousScreen),
+    }
+}
 
-        button.wait_for_rising_edge().await;
+// what the push switch of the rotary encoder does, turning the knob changes the screen
+// short press: select (pin / unpin the current screen), long press: backlight
+#[cfg(feature = "encoder")]
+fn encoder_switch_command(gesture: Gesture) -> Option<UiCommand<MAX_PASSAGES>> {
+    match gesture {
+        Gesture::ShortPress => Some(UiCommand::TogglePinned),
+        Gesture::LongPress => Some(UiCommand::ToggleBacklight),
+        Gesture::DoubleClick => None,
+    }
+}
+
+// one task per button, each with its own mapping of the gestures to UI commands
+// the button is active low (pull-up)
+#[embassy_executor::task(pool_size = 2)]
+async fn button_task(mut button: Input<'static>, command: fn(Gesture) -> Option<UiCommand<MAX_PASSAGES>>) {
+    let mut recognizer = GestureRecognizer::new(GESTURE_TIMINGS);
+    loop {
+        let edge = async {
+            if button.is_low() {
+                button.wait_for_rising_edge().await;
+                false
+            } else {
+                button.wait_for_falling_edge().await;
+                true
+            }
+        };
+        let gesture = match recognizer.deadline() {
+            Some(deadline) => match select(edge, Timer::at(deadline)).await {
+                Either::First(true) => recognizer.pressed(Instant::now()),
+                Either::First(false) => recognizer.released(Instant::now()),
+                Either::Second(()) => recognizer.timeout(Instant::now()),
+            },
+            None => match edge.await {
+                true => recognizer.pressed(Instant::now()),
+                false => recognizer.released(Instant::now()),
+            },
+        };
+
+        if let Some(gesture) = gesture {
+            esp_println::println!("BOUTON {:?}", gesture);
+            if let Some(cmd) = command(gesture) {
+                UI_CH.send(cmd).await;
+            }
+        }
     }
 }
 
@@ -620,3 +880,21 @@ async fn watchdog_task(mut wdt: Wdt<TIMG0<'static>>) {
         wdt.feed();
     }
 }
+
+// clockwise: next screen, counter clockwise: previous screen
+// the decoder is fed on every edge of CLK and DT, the levels are read again after the edge
+#[cfg(feature = "encoder")]
+#[embassy_executor::task]
+async fn encoder_task(mut clk: Input<'static>, mut dt: Input<'static>) {
+    let mut decoder = QuadratureDecoder::new(clk.is_high(), dt.is_high(), ENCODER_TRANSITIONS_PER_DETENT);
+    loop {
+        select(clk.wait_for_any_edge(), dt.wait_for_any_edge()).await;
+        let cmd = match decoder.update(clk.is_high(), dt.is_high()) {
+            Some(Turn::Clockwise) => UiCommand::NextScreen,
+            Some(Turn::CounterClockwise) => UiCommand::PreviousScreen,
+            None => continue,
+        };
+        esp_println::println!("ENCODEUR {:?}", cmd);
+        UI_CH.send(cmd).await;
+    }
+}
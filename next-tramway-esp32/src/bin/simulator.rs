@@ -0,0 +1,82 @@
+// Host simulator of the LCD, plays a scenario (see `sim::Scenario` for the format) and prints every screen
+// usage: cargo simulate [--ansi] [--1602] [--a02] [SCENARIO_FILE]
+// the scenario is read from stdin when no file is given
+// --a02 simulates the european character ROM (more accented letters without custom glyphs)
+// --ansi redraws the screen in place, waiting between the steps, instead of printing all the screens one after the other
+
+use std::io::Read;
+use std::time::Duration as StdDuration;
+
+use embassy_time::Duration;
+use next_tramway_esp32::{
+    display::Staleness,
+    lcd::{LcdGeometry, LcdRom},
+    sim::{Scenario, Simulator, Step},
+};
+
+// same thresholds as the firmware
+const STALE_AFTER_SECS: u64 = 2 * 60;
+const NO_DATA_AFTER_SECS: u64 = 10 * 60;
+
+fn main() {
+    let mut ansi = false;
+    let mut geometry = LcdGeometry::L2004;
+    let mut rom = LcdRom::A00;
+    let mut path = None;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--ansi" => ansi = true,
+            "--1602" => geometry = LcdGeometry::L1602,
+            "--a02" => rom = LcdRom::A02,
+            _ => path = Some(arg),
+        }
+    }
+
+    let script = match &path {
+        Some(path) => std::fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("Couldn't read {path}: {e}");
+            std::process::exit(1);
+        }),
+        None => {
+            let mut script = String::new();
+            std::io::stdin().read_to_string(&mut script).expect("Couldn't read stdin");
+            script
+        }
+    };
+
+    let mut simulator = Simulator::new(
+        geometry,
+        rom,
+        Staleness {
+            stale_after: Duration::from_secs(STALE_AFTER_SECS),
+            no_data_after: Duration::from_secs(NO_DATA_AFTER_SECS),
+        },
+    );
+
+    for step in Scenario::new(&script) {
+        let step = match step {
+            Ok(step) => step,
+            Err(e) => {
+                eprintln!("line {}: {:?}", e.line, e.kind);
+                std::process::exit(1);
+            }
+        };
+        let description = match &step {
+            Step::Command(cmd) => format!("{cmd:?}"),
+            Step::Wait(duration) => format!("wait {}s", duration.as_secs()),
+        };
+        simulator.apply(step);
+
+        let elapsed = simulator.state.now.as_secs();
+        if ansi {
+            // clear the terminal and draw at the top left
+            print!("\x1b[2J\x1b[H");
+            println!("t={elapsed}s {description}");
+            println!("{}", simulator.display);
+            std::thread::sleep(StdDuration::from_millis(800));
+        } else {
+            println!("== t={elapsed}s {description}");
+            println!("{}", simulator.display);
+        }
+    }
+}
@@ -0,0 +1,189 @@
+// Compact binary payload of the line topics, for the links where every byte counts
+// Sent on `next-tramway/line/<line>/<direction_id>/bin`: one byte of schema version, then the `DirectionUpdate`
+// encoded with postcard (varints, strings prefixed by their length, no field names)
+// The type is shared with the publishers written in Rust, which encode it with `encode`. Any change of its fields
+// changes the encoding, so it needs a new SCHEMA_VERSION: the old version stays decodable as long as backends send it,
+// and a device receiving a version it doesn't know yet says so instead of showing garbage
+// - version 1: first version
+// - version 2: absolute arrival time of the passages
+
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::display::{
+    ArrivalKind, DEFAULT_PASSAGES, Destination, Disruption, LineId, LineName, TramNextPassage, UiCommand, UpdateAt,
+};
+use crate::protocol::ParseError;
+
+pub const SCHEMA_VERSION: u8 = 2;
+
+// same content as the JSON payload, the line id and the direction id come from the topic
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DirectionUpdate<const PASSAGES: usize = DEFAULT_PASSAGES> {
+    pub line: LineName,
+    pub passages: Vec<Passage, PASSAGES>,
+    pub update_at: UpdateAt,
+    pub disruption: Option<Disruption>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Passage {
+    pub destination: Destination,
+    pub minutes: u8,
+    pub realtime: bool, // false for the timetable times, like `S` in the text format
+    pub at: Option<u64>, // unix time of the arrival
+}
+
+// previous versions, still sent by the backends that haven't been upgraded
+mod v1 {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct DirectionUpdate<const PASSAGES: usize> {
+        pub line: LineName,
+        pub passages: Vec<Passage, PASSAGES>,
+        pub update_at: UpdateAt,
+        pub disruption: Option<Disruption>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct Passage {
+        pub destination: Destination,
+        pub minutes: u8,
+        pub realtime: bool,
+    }
+
+    impl<const PASSAGES: usize> From<DirectionUpdate<PASSAGES>> for super::DirectionUpdate<PASSAGES> {
+        fn from(update: DirectionUpdate<PASSAGES>) -> Self {
+            let passages = update
+                .passages
+                .into_iter()
+                .map(|passage| super::Passage { destination: passage.destination, minutes: passage.minutes, realtime: passage.realtime, at: None })
+                .collect();
+            super::DirectionUpdate { line: update.line, passages, update_at: update.update_at, disruption: update.disruption }
+        }
+    }
+}
+
+impl<const PASSAGES: usize> DirectionUpdate<PASSAGES> {
+    pub fn into_command(self, line_id: LineId, direction_id: usize) -> UiCommand<PASSAGES> {
+        let next_passages = self
+            .passages
+            .into_iter()
+            .map(|passage| TramNextPassage {
+                destination: passage.destination,
+                relative_arrival: passage.minutes,
+                arrival_at: passage.at,
+                arrival_kind: if passage.realtime { ArrivalKind::Realtime } else { ArrivalKind::Scheduled },
+            })
+            .collect();
+        UiCommand::UpdateDirection {
+            line_id,
+            line: self.line,
+            direction_id,
+            next_passages,
+            update_at: self.update_at,
+            disruption: self.disruption,
+            staleness: None,
+        }
+    }
+}
+
+// writes the payload in `buffer` and returns the part used, an error if it doesn't fit
+pub fn encode<'a, const PASSAGES: usize>(update: &DirectionUpdate<PASSAGES>, buffer: &'a mut [u8]) -> Result<&'a mut [u8], postcard::Error> {
+    let (version, rest) = buffer.split_first_mut().ok_or(postcard::Error::SerializeBufferFull)?;
+    *version = SCHEMA_VERSION;
+    let len = postcard::to_slice(update, rest)?.len();
+    Ok(&mut buffer[..1 + len])
+}
+
+// the strings and the passages have the capacities of the device: a publisher using the same type can't send
+// more, but one built with more passages gets an `InvalidBinary` rather than an overflow of a given field
+pub fn decode<const PASSAGES: usize>(payload: &[u8]) -> Result<DirectionUpdate<PASSAGES>, ParseError> {
+    match payload.split_first() {
+        Some((1, rest)) => postcard::from_bytes::<v1::DirectionUpdate<PASSAGES>>(rest).map(Into::into).map_err(|_| ParseError::InvalidBinary),
+        Some((&SCHEMA_VERSION, rest)) => postcard::from_bytes(rest).map_err(|_| ParseError::InvalidBinary),
+        Some((&version, _)) => Err(ParseError::UnsupportedVersion(version)),
+        None => Err(ParseError::InvalidBinary),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update() -> DirectionUpdate {
+        let mut passages = Vec::new();
+        for (destination, minutes, realtime, at) in [("Plaine des sports", 3, true, Some(1_760_625_480)), ("Université | Condillac", 8, false, None)] {
+            let _ = passages.push(Passage { destination: Destination::try_from(destination).unwrap(), minutes, realtime, at });
+        }
+        DirectionUpdate {
+            line: LineName::try_from("Tram C").unwrap(),
+            passages,
+            update_at: UpdateAt::try_from("14:35:10").unwrap(),
+            disruption: Some(Disruption::try_from("Arret Gares non desservi").unwrap()),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut buffer = [0; 256];
+        let payload = encode(&update(), &mut buffer).unwrap();
+        assert_eq!(payload[0], SCHEMA_VERSION);
+        // 78 bytes of text, and only 19 bytes for the version, the lengths, the minutes, the flags and the arrival time
+        assert_eq!(payload.len(), 97);
+        assert_eq!(decode(payload), Ok(update()));
+
+        let without_passages = DirectionUpdate { passages: Vec::new(), disruption: None, ..update() };
+        let payload = encode(&without_passages, &mut buffer).unwrap();
+        assert_eq!(decode::<3>(payload), Ok(without_passages));
+    }
+
+    #[test]
+    fn becomes_a_direction_update() {
+        let UiCommand::UpdateDirection { line_id, line, direction_id, next_passages, update_at, disruption, .. } =
+            update().into_command(LineId::try_from("C").unwrap(), 2)
+        else {
+            panic!("not a direction update");
+        };
+        assert_eq!((line_id.as_str(), line.as_str(), direction_id, update_at.as_str()), ("C", "Tram C", 2, "14:35:10"));
+        assert_eq!(next_passages[1].destination, "Université | Condillac");
+        assert_eq!(next_passages[1].arrival_kind, ArrivalKind::Scheduled);
+        assert_eq!(next_passages[0].arrival_at, Some(1_760_625_480));
+        assert_eq!(disruption.as_deref(), Some("Arret Gares non desservi"));
+    }
+
+    #[test]
+    fn still_decodes_version_1() {
+        let mut passages = Vec::new();
+        let _ = passages.push(v1::Passage { destination: Destination::try_from("Gare").unwrap(), minutes: 3, realtime: true });
+        let update: v1::DirectionUpdate<3> =
+            v1::DirectionUpdate { line: LineName::try_from("Tram C").unwrap(), passages, update_at: UpdateAt::try_from("14:35:10").unwrap(), disruption: None };
+        let mut buffer = [0; 64];
+        buffer[0] = 1;
+        let len = postcard::to_slice(&update, &mut buffer[1..]).unwrap().len();
+
+        let decoded = decode::<3>(&buffer[..1 + len]).unwrap();
+        assert_eq!(decoded.line, "Tram C");
+        assert_eq!(decoded.passages[0].destination, "Gare");
+        assert_eq!(decoded.passages[0].minutes, 3);
+        assert_eq!(decoded.passages[0].at, None);
+    }
+
+    #[test]
+    fn rejects_other_versions_and_bad_payloads() {
+        let mut buffer = [0; 256];
+        let payload = encode(&update(), &mut buffer).unwrap();
+        payload[0] = SCHEMA_VERSION + 1;
+        assert_eq!(decode::<3>(payload), Err(ParseError::UnsupportedVersion(SCHEMA_VERSION + 1)));
+
+        payload[0] = SCHEMA_VERSION;
+        let len = payload.len();
+        assert_eq!(decode::<3>(&payload[..len - 10]), Err(ParseError::InvalidBinary));
+        // more passages than the device can keep
+        assert_eq!(decode::<1>(payload), Err(ParseError::InvalidBinary));
+        assert_eq!(decode::<3>(&[]), Err(ParseError::InvalidBinary));
+
+        assert!(encode(&update(), &mut [0; 16]).is_err());
+    }
+}
@@ -0,0 +1,211 @@
+// Layout shared by the pixel based displays (OLED, e-paper)
+// Each display provides a framebuffer implementing `Canvas`, the UI state is drawn into it with `render_state`
+// and the display driver then decides how to send the framebuffer to the screen
+
+use core::fmt::Write;
+use embassy_time::Instant;
+
+use crate::clock::LocalTime;
+use crate::display::{ArrivalKind, Freshness, MessagePlacement, TramDirectionState, UiState, WallClock};
+use crate::font;
+
+pub(crate) const ROW_HEIGHT: u16 = 11; // glyph height + 3 pixels of spacing between the passages
+pub(crate) const PASSAGES_Y: u16 = 12; // first passage, below the line name and the separator
+const MESSAGE_ROW_HEIGHT: u16 = font::GLYPH_HEIGHT as u16 + 2;
+const MINUTES_GAP: u16 = 4; // minimum space between a destination and its minutes
+const CLOCK_SCALE: u16 = 3; // size of the time on the clock screen, in pixels per pixel of the font
+
+pub trait Canvas {
+    fn width(&self) -> u16;
+    fn height(&self) -> u16;
+    // pixels outside of the canvas must be silently ignored, so text can be drawn partially
+    fn set_pixel(&mut self, x: u16, y: u16, on: bool);
+    fn clear(&mut self);
+
+    fn draw_hline(&mut self, y: u16) {
+        for x in 0..self.width() {
+            self.set_pixel(x, y, true);
+        }
+    }
+
+    // draw `text` with its top left corner at (x, y), returns the x coordinate right after the text
+    fn draw_text(&mut self, x: u16, y: u16, text: &str) -> u16 {
+        let mut cursor = x;
+        for (i, c) in text.chars().enumerate() {
+            if i > 0 {
+                cursor += font::LETTER_SPACING as u16;
+            }
+            for column in font::glyph(c) {
+                for bit in 0..font::GLYPH_HEIGHT {
+                    if column & (1 << bit) != 0 {
+                        self.set_pixel(cursor, y + bit as u16, true);
+                    }
+                }
+                cursor += 1;
+            }
+        }
+        cursor
+    }
+
+    // draw `text` so that it ends right before `right`
+    fn draw_text_right(&mut self, right: u16, y: u16, text: &str) {
+        let width = font::text_width(text);
+        self.draw_text(right.saturating_sub(width), y, text);
+    }
+}
+
+// longest prefix of `text` that fits in `max_width` pixels
+pub(crate) fn fit_text(text: &str, max_width: u16) -> &str {
+    let mut end = 0;
+    for (i, c) in text.char_indices() {
+        let candidate = &text[..i + c.len_utf8()];
+        if font::text_width(candidate) > max_width {
+            break;
+        }
+        end = candidate.len();
+    }
+    &text[..end]
+}
+
+pub(crate) fn footer_y<C: Canvas>(canvas: &C) -> u16 {
+    canvas.height() - font::GLYPH_HEIGHT as u16
+}
+
+// draw the UI state into the canvas, the layout is:
+// - line name, underlined
+// - up to 3 passages: destination on the left, minutes on the right
+// - timestamp of the last update on the bottom right (with a warning on the left if the data is old),
+//   or the disruption sent by the backend while the data is fresh
+// - or a warning message on the bottom row instead of the timestamp, see `UiState::visible_message`
+// the clock screen (`UiState::clock_screen`) has the time in big digits instead, and the date below
+pub fn render_state<C: Canvas, const LINES: usize, const DIRECTIONS: usize, const PASSAGES: usize>(
+    canvas: &mut C,
+    state: &UiState<LINES, DIRECTIONS, PASSAGES>,
+) {
+    canvas.clear();
+
+    let message = state.visible_message();
+    if let Some((message, MessagePlacement::FullScreen)) = message {
+        render_message(canvas, &message.text);
+        return;
+    }
+
+    let width = canvas.width();
+    if let Some(local) = state.clock_screen() {
+        render_clock(canvas, &local);
+    } else {
+        let Some((line, direction)) = state.current_screen() else { return };
+        canvas.draw_text(0, 0, fit_text(&line.line, width));
+        canvas.draw_hline(font::GLYPH_HEIGHT as u16 + 1);
+        render_direction(canvas, direction, state.now, state.clock.as_ref());
+    }
+
+    if let Some((message, MessagePlacement::Banner)) = message {
+        // erase the footer, the passages above stay readable
+        let footer_y = footer_y(canvas);
+        for y in footer_y.saturating_sub(2)..canvas.height() {
+            for x in 0..width {
+                canvas.set_pixel(x, y, false);
+            }
+        }
+        canvas.draw_hline(footer_y.saturating_sub(2));
+        canvas.draw_text(0, footer_y, fit_text(&message.text, width));
+    }
+}
+
+fn render_direction<C: Canvas, const PASSAGES: usize>(
+    canvas: &mut C,
+    direction: &TramDirectionState<PASSAGES>,
+    now: Instant,
+    clock: Option<&WallClock>,
+) {
+    let width = canvas.width();
+    let footer_y = footer_y(canvas);
+
+    let freshness = direction.freshness(now);
+    if freshness == Freshness::NoData {
+        canvas.draw_text(0, PASSAGES_Y, "Pas de donnees depuis");
+        canvas.draw_text_right(width, footer_y, &direction.update_at);
+        return;
+    }
+
+    let mut y = PASSAGES_Y;
+    let mut minutes: heapless::String<8> = heapless::String::new();
+    for (passage, remaining_minutes) in direction.remaining_passages(now, clock).take(3) {
+        minutes.clear();
+        // scheduled times are marked with a `*` like on the LCD
+        if passage.arrival_kind == ArrivalKind::Scheduled {
+            let _ = minutes.push('*');
+        }
+        let _ = write!(minutes, "{} min", remaining_minutes);
+
+        let destination_width = width.saturating_sub(font::text_width(&minutes) + MINUTES_GAP);
+        canvas.draw_text(0, y, fit_text(&passage.destination, destination_width));
+        canvas.draw_text_right(width, y, &minutes);
+        y += ROW_HEIGHT;
+    }
+    if y == PASSAGES_Y {
+        canvas.draw_text(0, PASSAGES_Y, "Pas de passage dans");
+        canvas.draw_text(0, PASSAGES_Y + ROW_HEIGHT, "l'heure...");
+    }
+
+    match (freshness, &direction.disruption) {
+        // cut on the right, there is no scrolling on these displays
+        (Freshness::Fresh, Some(disruption)) => {
+            canvas.draw_text(0, footer_y, fit_text(disruption, width));
+        }
+        _ => {
+            if freshness == Freshness::Stale {
+                canvas.draw_text(0, footer_y, "ANCIENNES");
+            }
+            canvas.draw_text_right(width, footer_y, &direction.update_at);
+        }
+    }
+}
+
+// time and date centered, the time scaled up
+fn render_clock<C: Canvas>(canvas: &mut C, local: &LocalTime) {
+    let width = canvas.width();
+    let mut text: heapless::String<24> = heapless::String::new();
+    let _ = write!(text, "{:02}:{:02}", local.hour, local.minute);
+    let time_height = font::GLYPH_HEIGHT as u16 * CLOCK_SCALE;
+    let time_y = footer_y(canvas).saturating_sub(time_height) / 2;
+    let mut x = width.saturating_sub(font::text_width(&text) * CLOCK_SCALE) / 2;
+    for (i, c) in text.chars().enumerate() {
+        if i > 0 {
+            x += font::LETTER_SPACING as u16 * CLOCK_SCALE;
+        }
+        for column in font::glyph(c) {
+            for bit in 0..font::GLYPH_HEIGHT {
+                if column & (1 << bit) != 0 {
+                    for (dx, dy) in (0..CLOCK_SCALE).flat_map(|dx| (0..CLOCK_SCALE).map(move |dy| (dx, dy))) {
+                        canvas.set_pixel(x + dx, time_y + bit as u16 * CLOCK_SCALE + dy, true);
+                    }
+                }
+            }
+            x += CLOCK_SCALE;
+        }
+    }
+
+    text.clear();
+    let _ = write!(text, "{} {} {} {}", local.weekday_name(), local.day, local.month_name(), local.year);
+    let date = fit_text(&text, width);
+    canvas.draw_text(width.saturating_sub(font::text_width(date)) / 2, footer_y(canvas), date);
+}
+
+// wrap the message on as many rows as needed, cutting in the middle of the words like `lcd::wrap_text`
+fn render_message<C: Canvas>(canvas: &mut C, message: &str) {
+    let width = canvas.width();
+    let mut y = 0;
+    let mut rest = message;
+    while !rest.is_empty() && y < canvas.height() {
+        let mut row = fit_text(rest, width);
+        if row.is_empty() {
+            // single character wider than the canvas, can't happen with our font but avoid looping forever
+            row = &rest[..rest.chars().next().map_or(rest.len(), char::len_utf8)];
+        }
+        canvas.draw_text(0, y, row);
+        rest = &rest[row.len()..];
+        y += MESSAGE_ROW_HEIGHT;
+    }
+}
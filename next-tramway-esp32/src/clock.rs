@@ -0,0 +1,175 @@
+// Local time of the device, from the UTC time given by SNTP (see the sntp module)
+// The stop is in France: Europe/Paris is UTC+1 (CET), and UTC+2 (CEST) from the last Sunday of March to the last
+// Sunday of October, both changes at 01:00 UTC. Hardcoded rather than read from a tz database, there is no room for
+// one and these rules haven't changed since 1996
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+const CET_OFFSET_SECS: u64 = 60 * 60;
+const CEST_OFFSET_SECS: u64 = 2 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalTime {
+    pub year: u16,
+    pub month: u8, // 1 to 12
+    pub day: u8, // 1 to 31
+    pub weekday: u8, // 0 for Monday to 6 for Sunday
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub utc_offset_secs: u32, // CET or CEST
+}
+
+// abbreviated like in the French calendars, the longest date ("mer. 30 sept. 2026") fits on a row of the LCD
+const WEEKDAYS: [&str; 7] = ["lun.", "mar.", "mer.", "jeu.", "ven.", "sam.", "dim."];
+const MONTHS: [&str; 12] = ["janv.", "févr.", "mars", "avr.", "mai", "juin", "juil.", "août", "sept.", "oct.", "nov.", "déc."];
+
+impl LocalTime {
+    pub fn seconds_of_day(&self) -> u32 {
+        self.hour as u32 * 3600 + self.minute as u32 * 60 + self.second as u32
+    }
+
+    pub fn weekday_name(&self) -> &'static str {
+        WEEKDAYS[self.weekday as usize]
+    }
+
+    pub fn month_name(&self) -> &'static str {
+        MONTHS[self.month as usize - 1]
+    }
+}
+
+// offset of Europe/Paris at the given UTC time
+pub fn utc_offset_secs(unix_secs: u64) -> u64 {
+    let (year, _, _) = civil_from_days(unix_secs / SECS_PER_DAY);
+    let summer_starts = days_from_civil(year, 3, last_sunday(year, 3)) * SECS_PER_DAY + 3600;
+    let summer_ends = days_from_civil(year, 10, last_sunday(year, 10)) * SECS_PER_DAY + 3600;
+    if (summer_starts..summer_ends).contains(&unix_secs) { CEST_OFFSET_SECS } else { CET_OFFSET_SECS }
+}
+
+pub fn local_time(unix_secs: u64) -> LocalTime {
+    let offset = utc_offset_secs(unix_secs);
+    let local = unix_secs + offset;
+    let days = local / SECS_PER_DAY;
+    let (year, month, day) = civil_from_days(days);
+    let secs = local % SECS_PER_DAY;
+    LocalTime {
+        year,
+        month,
+        day,
+        weekday: weekday(days),
+        hour: (secs / 3600) as u8,
+        minute: (secs / 60 % 60) as u8,
+        second: (secs % 60) as u8,
+        utc_offset_secs: offset as u32,
+    }
+}
+
+// "14:35:10" or "14:35", the `update_at` sent by the backends, None for anything else
+pub fn parse_time_of_day(text: &str) -> Option<u32> {
+    let mut parts = text.trim().split(':');
+    let mut field = |max: u32| parts.next().and_then(|part| part.parse::<u32>().ok()).filter(|&value| value < max);
+    let hour = field(24)?;
+    let minute = field(60)?;
+    let second = match parts.next() {
+        Some(part) => part.parse::<u32>().ok().filter(|&value| value < 60)?,
+        None => 0,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(hour * 3600 + minute * 60 + second)
+}
+
+// 1970-01-01 was a Thursday
+fn weekday(days: u64) -> u8 {
+    ((days + 3) % 7) as u8
+}
+
+fn last_sunday(year: u16, month: u8) -> u8 {
+    // only used for March and October
+    let last_day = 31;
+    let last_weekday = weekday(days_from_civil(year, month, last_day));
+    last_day - (last_weekday + 1) % 7
+}
+
+// days since 1970-01-01 of a date of the proleptic gregorian calendar, after 1970
+// from http://howardhinnant.github.io/date_algorithms.html, with the years starting in March so February comes last
+fn days_from_civil(year: u16, month: u8, day: u8) -> u64 {
+    let year = year as u64 - (month <= 2) as u64;
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let month = month as u64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as u64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: u64) -> (u16, u8, u8) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month + 2) / 5 + 1) as u8;
+    let month = if month < 10 { month + 3 } else { month - 9 } as u8;
+    let year = (era * 400 + year_of_era + (month <= 2) as u64) as u16;
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(local: &LocalTime) -> (u16, u8, u8, u8, u8, u8) {
+        (local.year, local.month, local.day, local.hour, local.minute, local.second)
+    }
+
+    #[test]
+    fn converts_to_the_calendar() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(days_from_civil(2024, 2, 29), 19_782);
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(19_783), (2024, 3, 1));
+        for days in [0, 365, 11_016, 19_782, 20_454, 47_482] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+
+        // 2025-10-16 12:38:00 UTC, a Thursday
+        let local = local_time(1_760_618_280);
+        assert_eq!(time(&local), (2025, 10, 16, 14, 38, 0));
+        assert_eq!(local.weekday, 3);
+        assert_eq!((local.weekday_name(), local.month_name()), ("jeu.", "oct."));
+        assert_eq!(local.seconds_of_day(), 14 * 3600 + 38 * 60);
+    }
+
+    #[test]
+    fn follows_the_summer_time() {
+        assert_eq!((last_sunday(2025, 3), last_sunday(2025, 10)), (30, 26));
+        assert_eq!((last_sunday(2026, 3), last_sunday(2026, 10)), (29, 25));
+
+        // 2025-03-30, 02:00 CET becomes 03:00 CEST
+        let change = 1_743_296_400;
+        assert_eq!(time(&local_time(change - 1)), (2025, 3, 30, 1, 59, 59));
+        assert_eq!(time(&local_time(change)), (2025, 3, 30, 3, 0, 0));
+        assert_eq!(local_time(change).utc_offset_secs, 7200);
+
+        // 2025-10-26, 03:00 CEST is 02:00 CET again
+        let change = 1_761_440_400;
+        assert_eq!(time(&local_time(change - 1)), (2025, 10, 26, 2, 59, 59));
+        assert_eq!(time(&local_time(change)), (2025, 10, 26, 2, 0, 0));
+        assert_eq!(local_time(change).utc_offset_secs, 3600);
+
+        // new year in Paris, an hour before UTC
+        assert_eq!(time(&local_time(1_767_225_600 - 3600)), (2026, 1, 1, 0, 0, 0));
+    }
+
+    #[test]
+    fn parses_the_update_time() {
+        assert_eq!(parse_time_of_day("14:35:10"), Some(14 * 3600 + 35 * 60 + 10));
+        assert_eq!(parse_time_of_day("07:05"), Some(7 * 3600 + 5 * 60));
+        for invalid in ["", "14", "24:00:00", "14:60", "14:35:10:00", "il y a 2 min", "14:-1"] {
+            assert_eq!(parse_time_of_day(invalid), None, "{invalid}");
+        }
+    }
+}
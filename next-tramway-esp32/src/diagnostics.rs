@@ -0,0 +1,166 @@
+// Counters of the data that didn't fit in the device, and the warnings published for the backend author
+// A payload field too big for the buffers is truncated by the protocol module, a line or direction that doesn't fit
+// in the UI state pushes out the oldest one: both are counted here so the problem can be spotted without a serial console
+// The MQTT task and the renderer record into the same counters, hence the atomics
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::display::{Destination, Disruption, LineId, LineName, StateOverflow, UpdateAt};
+use crate::protocol::{Field, ParseError};
+
+// topic the warnings are published on, without the retain flag
+pub const WARNING_TOPIC: &str = "next-tramway/status/warning";
+
+pub type Warning = heapless::String<128>;
+
+const FIELDS: usize = 6;
+
+pub struct Diagnostics {
+    overflows: [AtomicU32; FIELDS], // payloads truncated (rejected for the line id) because of this field, indexed by `Field as usize`
+    parse_errors: AtomicU32, // payloads rejected for another reason
+    dropped_lines: AtomicU32,
+    dropped_directions: AtomicU32,
+}
+
+impl Diagnostics {
+    pub const fn new() -> Self {
+        Diagnostics {
+            overflows: [const { AtomicU32::new(0) }; FIELDS],
+            parse_errors: AtomicU32::new(0),
+            dropped_lines: AtomicU32::new(0),
+            dropped_directions: AtomicU32::new(0),
+        }
+    }
+
+    pub fn record_parse_error(&self, error: &ParseError) {
+        match error {
+            ParseError::Overflow(field) => self.record_overflow(*field),
+            _ => {
+                self.parse_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // the payload was applied with `field` truncated
+    pub fn record_overflow(&self, field: Field) {
+        self.overflows[field as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_state_overflow(&self, overflow: &StateOverflow) {
+        let counter = match overflow {
+            StateOverflow::Lines { .. } => &self.dropped_lines,
+            StateOverflow::Directions { .. } => &self.dropped_directions,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn overflows(&self, field: Field) -> u32 {
+        self.overflows[field as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn parse_errors(&self) -> u32 {
+        self.parse_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped_lines(&self) -> u32 {
+        self.dropped_lines.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped_directions(&self) -> u32 {
+        self.dropped_directions.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Diagnostics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// one line summary for the logs
+impl core::fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "overflows: line id {}, line name {}, destination {}, passages {}, timestamp {}, disruption {} / other parse errors {} / dropped lines {}, directions {}",
+            self.overflows(Field::LineId),
+            self.overflows(Field::LineName),
+            self.overflows(Field::Destination),
+            self.overflows(Field::Passages),
+            self.overflows(Field::UpdateAt),
+            self.overflows(Field::Disruption),
+            self.parse_errors(),
+            self.dropped_lines(),
+            self.dropped_directions(),
+        )
+    }
+}
+
+// the payload received on `topic` had `field` truncated (or was rejected, for the line id), PASSAGES is the capacity of the device
+pub fn overflow_warning<const PASSAGES: usize>(topic: &str, field: Field) -> Warning {
+    let mut warning = Warning::new();
+    let (name, max_bytes) = match field {
+        Field::Passages => {
+            let _ = write!(warning, "{}: more than {} passages", topic, PASSAGES);
+            return warning;
+        }
+        Field::LineId => ("line id", LineId::new().capacity()),
+        Field::LineName => ("line name", LineName::new().capacity()),
+        Field::Destination => ("destination", Destination::new().capacity()),
+        Field::UpdateAt => ("timestamp", UpdateAt::new().capacity()),
+        Field::Disruption => ("disruption", Disruption::new().capacity()),
+    };
+    let _ = write!(warning, "{}: {} longer than {} bytes", topic, name, max_bytes);
+    warning
+}
+
+// LINES and DIRECTIONS are the capacities of the device
+pub fn state_overflow_warning<const LINES: usize, const DIRECTIONS: usize>(overflow: &StateOverflow) -> Warning {
+    let mut warning = Warning::new();
+    let _ = match overflow {
+        StateOverflow::Lines { dropped } => write!(warning, "more than {} lines, line {} dropped", LINES, dropped),
+        StateOverflow::Directions { line_id, dropped } => {
+            write!(warning, "more than {} directions on line {}, direction {} dropped", DIRECTIONS, line_id, dropped)
+        }
+    };
+    warning
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_each_kind_of_overflow() {
+        let diagnostics = Diagnostics::new();
+        diagnostics.record_overflow(Field::Passages);
+        diagnostics.record_overflow(Field::Passages);
+        diagnostics.record_overflow(Field::LineName);
+        diagnostics.record_parse_error(&ParseError::Overflow(Field::LineId));
+        diagnostics.record_parse_error(&ParseError::InvalidMinutes);
+        diagnostics.record_state_overflow(&StateOverflow::Lines { dropped: LineId::try_from("A").unwrap() });
+
+        assert_eq!(diagnostics.overflows(Field::Passages), 2);
+        assert_eq!(diagnostics.overflows(Field::LineName), 1);
+        assert_eq!(diagnostics.overflows(Field::LineId), 1);
+        assert_eq!(diagnostics.overflows(Field::Destination), 0);
+        assert_eq!(diagnostics.parse_errors(), 1);
+        assert_eq!(diagnostics.dropped_lines(), 1);
+        assert_eq!(diagnostics.dropped_directions(), 0);
+    }
+
+    #[test]
+    fn warnings_tell_what_doesnt_fit() {
+        assert_eq!(
+            overflow_warning::<3>("next-tramway/line/C/1", Field::Passages),
+            "next-tramway/line/C/1: more than 3 passages"
+        );
+        assert_eq!(
+            overflow_warning::<3>("next-tramway/line/C/1", Field::Destination),
+            "next-tramway/line/C/1: destination longer than 32 bytes"
+        );
+        let overflow = StateOverflow::Directions { line_id: LineId::try_from("C").unwrap(), dropped: 2 };
+        assert_eq!(state_overflow_warning::<8, 2>(&overflow), "more than 2 directions on line C, direction 2 dropped");
+    }
+}
@@ -3,121 +3,1215 @@
 
 // the rendering logic is implemented in the lcd module, which implements the TramDisplay trait for the Lcd struct
 
+use embassy_time::{Duration, Instant};
+
+use crate::clock::{self, LocalTime};
+
+// capacities of the state, given as const generics to `UiState`, `TramLineState`, `TramDirectionState` and `UiCommand`
+// the defaults fit a stop with a few lines on the 20x4 LCD, a big interchange stop or a larger display can pick its own
+pub const DEFAULT_LINES: usize = 8;
+pub const DEFAULT_DIRECTIONS: usize = 2; // the two sides of the platform
+pub const DEFAULT_PASSAGES: usize = 3;
+
+// sizes of the text fields, in bytes of UTF-8
+// unlike the capacities above they are tied to the payload format and to the layouts of the displays
+pub type LineId = heapless::String<16>;
+pub type LineName = heapless::String<16>;
+pub type Destination = heapless::String<32>;
+pub type UpdateAt = heapless::String<10>;
+pub type Disruption = heapless::String<64>;
+pub type Message = heapless::String<80>;
+
 #[derive(Debug)]
-pub enum UiCommand {
+pub enum UiCommand<const PASSAGES: usize = DEFAULT_PASSAGES> {
     UpdateDirection {
-        line: heapless::String<16>,
+        line_id: LineId,
+        line: LineName,
         direction_id: usize,
-        next_passages: heapless::Vec<TramNextPassage, 3>, 
-        update_at: heapless::String<10>
+        next_passages: heapless::Vec<TramNextPassage, PASSAGES>, 
+        update_at: UpdateAt,
+        disruption: Option<Disruption>, // only in the JSON payload
+        staleness: Option<Staleness>, // thresholds of this direction, `UiState::staleness` without them (only in the JSON payload)
     },
-    UpdateMessage(heapless::String<80>),
+    RemoveDirection { line_id: LineId, direction_id: usize }, // the line goes away with its last direction
+    RemoveLine { line_id: LineId },
+    UpdateMessage { text: Message, severity: Severity, expires_after: Option<Duration> }, // None: until it's replaced or cleared
+    ClearMessage { text: Message }, // only if the current message is still this one, a newer message stays
     NextScreen,
+    PreviousScreen,
+    ShowDirection { line_id: LineId, direction_id: usize }, // jump to a given screen, ignored if it doesn't exist (yet)
+    ShowClock, // ignored until the device has a clock
+    SetPinned(bool), // a pinned screen isn't changed by the automatic rotation
+    TogglePinned,
     ToggleBacklight,
-    SetBacklight(bool)
+    SetBacklight(bool),
+    SetAutoRotation(bool),
+    SetRotationDwell(Duration),
+    SetNightMode(bool),
+    SetTime { unix_secs: u64 }, // UTC time from a time source (SNTP...), taken as the time of `now` when the command is applied
+    Tick, // periodic render tick, moves `UiState::now` forward (countdown, staleness) and drives the automatic rotation
 }
 
 // main data structure representing the current state of the UI, which can be rendered by a TramDisplay implementation
 #[derive(Debug)]
-pub struct UiState {
-    pub lines: heapless::Vec<TramLineState, 8>, // next passages data
-    pub current_message: Option<heapless::String<80>>, // Log message to display, it's up to the display implementation to decide when (and if) to show it (e.g. only when there are no lines to display)
-    pub current_line: usize, // index of the currently displayed line in `lines`, used for cycling through lines when there are more lines than can be displayed at once
-    pub current_direction_id: usize, // id of the currently displayed direction for the current line  
-    pub backlight_on: bool // whether the backlight is on or off, used to control the backlight of the display
+pub struct UiState<
+    const LINES: usize = DEFAULT_LINES,
+    const DIRECTIONS: usize = DEFAULT_DIRECTIONS,
+    const PASSAGES: usize = DEFAULT_PASSAGES,
+> {
+    pub lines: heapless::Vec<TramLineState<DIRECTIONS, PASSAGES>, LINES>, // next passages data
+    pub current_message: Option<StatusMessage>, // status of the device, see `visible_message` for when it's shown
+    pub selected: Option<ScreenKey>, // screen currently displayed, the first one received until the user or the rotation changes it
+    pub line_order: &'static [&'static str], // line ids shown first, in this order, the other lines come after them (see `line_rank`)
+    pub backlight_on: bool, // whether the backlight is on or off, used to control the backlight of the display
+    pub now: Instant, // time of the last command or render tick, the renderers use it to count down the arrival minutes between two updates
+    pub staleness: Staleness, // thresholds of the directions whose updates don't give their own
+    pub rotation: Rotation,
+    pub next_rotation_at: Instant, // when the automatic rotation shows the next screen
+    pub pinned: bool, // the current screen stays until it's unpinned, whatever the rotation does
+    pub clock: Option<WallClock>, // None until a time source sets it, the absolute arrivals and `update_at` are ignored until then
+    pub clock_in_rotation: bool, // the clock is one more screen after the last direction, once the device has a clock
+    pub clock_selected: bool, // the clock screen is shown instead of `selected`
+    pub night_mode: bool, // only the clock is shown, the passages are still updated behind it
+}
+
+impl<const LINES: usize, const DIRECTIONS: usize, const PASSAGES: usize> UiState<LINES, DIRECTIONS, PASSAGES> {
+    // empty state, shown until the first command is received
+    pub fn new(staleness: Staleness, now: Instant) -> Self {
+        UiState {
+            lines: heapless::Vec::new(),
+            current_message: None,
+            selected: None,
+            line_order: &[],
+            backlight_on: true,
+            now,
+            staleness,
+            rotation: Rotation::default(),
+            next_rotation_at: now,
+            pinned: false,
+            clock: None,
+            clock_in_rotation: false,
+            clock_selected: false,
+            night_mode: false,
+        }
+    }
+}
+
+impl<const LINES: usize, const DIRECTIONS: usize, const PASSAGES: usize> UiState<LINES, DIRECTIONS, PASSAGES> {
+    // line and direction to display, None if there is no line yet
+    // falls back to the first screen if the selected one doesn't exist
+    pub fn current_screen(&self) -> Option<(&TramLineState<DIRECTIONS, PASSAGES>, &TramDirectionState<PASSAGES>)> {
+        let (line, direction) = self.shown_position()?;
+        let line = &self.lines[line];
+        Some((line, line.directions.get(direction)?))
+    }
+
+    // message to show and how, None if there is none or if it's hidden:
+    // - with nothing else to show (no lines and no clock yet), any message takes the whole screen (boot, connection...)
+    // - otherwise errors still take the whole screen, warnings are shown on the bottom row and infos are hidden
+    pub fn visible_message(&self) -> Option<(&StatusMessage, MessagePlacement)> {
+        let message = self.current_message.as_ref().filter(|m| !m.expired(self.now))?;
+        if self.lines.is_empty() && self.clock.is_none() {
+            return Some((message, MessagePlacement::FullScreen));
+        }
+        match message.severity {
+            Severity::Error => Some((message, MessagePlacement::FullScreen)),
+            Severity::Warning => Some((message, MessagePlacement::Banner)),
+            Severity::Info => None,
+        }
+    }
+
+    // local time of the stop, None until the device has a clock
+    pub fn local_time(&self) -> Option<LocalTime> {
+        self.clock.map(|clock| clock.local_time(self.now))
+    }
+
+    // time to show instead of the passages, None if a direction is shown (or if there is no clock yet):
+    // in night mode, on the clock screen of the rotation, or while there are no lines
+    pub fn clock_screen(&self) -> Option<LocalTime> {
+        if self.night_mode || self.clock_selected || self.lines.is_empty() {
+            self.local_time()
+        } else {
+            None
+        }
+    }
+
+    fn clock_available(&self) -> bool {
+        self.clock_in_rotation && self.clock.is_some()
+    }
+
+    // indices in `lines` and `directions` of the screen displayed
+    fn shown_position(&self) -> Option<(usize, usize)> {
+        if self.lines.is_empty() {
+            return None;
+        }
+        let selected = self.selected.as_ref().and_then(|key| {
+            let line = self.lines.iter().position(|l| l.id == key.line_id)?;
+            let direction = self.lines[line].directions.iter().position(|d| d.direction_id == key.direction_id)?;
+            Some((line, direction))
+        });
+        Some(selected.unwrap_or((0, 0)))
+    }
+
+    fn select_position(&mut self, line: usize, direction: usize) {
+        self.selected = self.lines.get(line).and_then(|l| {
+            let direction = l.directions.get(direction)?;
+            Some(ScreenKey { line_id: l.id.clone(), direction_id: direction.direction_id })
+        });
+    }
+
+    // if the screen displayed is removed, the screen taking its place is shown and it's no longer pinned
+    fn remove_line(&mut self, index: usize) {
+        // nothing is selected if the line was emptied by `remove_direction`
+        let shown = !self.clock_selected && self.selected.as_ref().is_none_or(|key| key.line_id == self.lines[index].id);
+        self.lines.remove(index);
+        if shown {
+            self.pinned = false;
+            self.select_position(if index < self.lines.len() { index } else { 0 }, 0);
+        }
+    }
+
+    // leaves the line in place even without directions, it's up to the caller to remove it
+    fn remove_direction(&mut self, line: usize, index: usize) {
+        let shown = self.shown_position();
+        let directions = &mut self.lines[line].directions;
+        directions.remove(index);
+        if !self.clock_selected && shown == Some((line, index)) {
+            self.pinned = false;
+            let direction = index.min(directions.len().saturating_sub(1));
+            self.select_position(line, direction);
+        }
+    }
+
+    // go to the previous direction of the line, or to the last direction of the previous line
+    // the clock screen, if it's in the rotation, comes between the last and the first direction
+    fn previous_screen(&mut self) {
+        let Some((line, direction)) = self.shown_position() else { return };
+        let lines = &self.lines;
+
+        if self.clock_selected {
+            self.clock_selected = false;
+            let line = lines.len() - 1;
+            self.select_position(line, lines[line].directions.len().saturating_sub(1));
+        } else if direction > 0 {
+            self.select_position(line, direction - 1);
+        } else if line == 0 && self.clock_available() {
+            self.clock_selected = true;
+        } else {
+            let line = (line + lines.len() - 1) % lines.len();
+            let direction = lines[line].directions.len().saturating_sub(1);
+            self.select_position(line, direction);
+        }
+    }
+
+    // go to the next direction of the line, or to the first direction of the next line
+    fn next_screen(&mut self) {
+        let Some((line, direction)) = self.shown_position() else { return };
+        let lines = &self.lines;
+
+        if self.clock_selected {
+            self.clock_selected = false;
+            self.select_position(0, 0);
+        } else if direction + 1 < lines[line].directions.len() {
+            self.select_position(line, direction + 1);
+        } else if line + 1 == lines.len() && self.clock_available() {
+            self.clock_selected = true;
+        } else {
+            self.select_position((line + 1) % lines.len(), 0);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Info, // boot steps, gone once the data arrives
+    Warning,
+    Error, // the data shown can't be trusted, e.g. the connection to the MQTT server is lost
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusMessage {
+    pub text: Message,
+    pub severity: Severity,
+    pub expires_at: Option<Instant>,
+}
+
+impl StatusMessage {
+    fn expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessagePlacement {
+    FullScreen, // instead of the passages
+    Banner, // on the bottom row, over the update time
+}
+
+// a screen is one direction of a line, identified by the ids of its MQTT topic
+// unlike the indices in `lines`, it stays the same when other lines are added or removed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScreenKey {
+    pub line_id: LineId,
+    pub direction_id: usize,
+}
+
+// lines are kept sorted by this key: first the lines of `line_order`, in that order, then the others by id
+// shorter ids first, so "C2" comes before "C11"
+fn line_rank<'a>(line_order: &[&str], id: &'a str) -> (usize, usize, &'a str) {
+    let rank = line_order.iter().position(|l| *l == id).unwrap_or(line_order.len());
+    (rank, id.len(), id)
+}
+
+// automatic cycling through all the line/direction screens, on top of the button
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rotation {
+    pub enabled: bool,
+    pub dwell: Duration, // how long each screen stays before the next one is shown
+    pub pause_after_press: Duration, // after a manual change the rotation waits this long, so the chosen screen can be read
+}
+
+impl Default for Rotation {
+    // disabled, the screens only change with the button unless it's enabled by the config or an MQTT command
+    fn default() -> Self {
+        Rotation {
+            enabled: false,
+            dwell: Duration::from_secs(10),
+            pause_after_press: Duration::from_secs(60),
+        }
+    }
+}
+
+// time of day of the device, kept as the UTC time at a given instant of the monotonic clock
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WallClock {
+    pub unix_secs: u64, // seconds since 1970-01-01 UTC, at `set_at`
+    pub set_at: Instant,
+}
+
+impl WallClock {
+    pub fn unix_secs(&self, now: Instant) -> u64 {
+        self.unix_secs + now.checked_duration_since(self.set_at).unwrap_or(Duration::from_ticks(0)).as_secs()
+    }
+
+    pub fn local_time(&self, now: Instant) -> LocalTime {
+        clock::local_time(self.unix_secs(now))
+    }
+}
+
+// how long before `now` the backend computed the data, from its `update_at` (local time of the stop, like ours)
+// None without a clock or if `update_at` isn't a time of day
+// a time up to 12 hours ahead of the clock is the backend clock running a bit fast, not data from yesterday
+fn data_age(update_at: &str, clock: Option<&WallClock>, now: Instant) -> Option<Duration> {
+    const DAY: u32 = 24 * 60 * 60;
+    let computed = clock::parse_time_of_day(update_at)?;
+    let local = clock?.local_time(now).seconds_of_day();
+    let age = (local + DAY - computed) % DAY;
+    Some(Duration::from_secs(if age > DAY / 2 { 0 } else { age as u64 }))
+}
+
+// when the data was computed by the backend, so a retained message or a message stuck in a queue starts as old as it is
+// without a clock, the reception is the best guess
+fn computed_at(update_at: &str, clock: Option<&WallClock>, now: Instant) -> Instant {
+    data_age(update_at, clock, now).map_or(now, |age| now.checked_sub(age).unwrap_or(Instant::from_ticks(0)))
+}
+
+// how old the data of a direction can get before the renderers warn about it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Staleness {
+    pub stale_after: Duration, // after this, the data is still shown but flagged as old
+    pub no_data_after: Duration, // after this, the data is considered lost and the passages are no longer shown
+}
+
+// the delays of the README, shared by the tests of the state and of the renderers
+#[cfg(test)]
+impl Default for Staleness {
+    fn default() -> Self {
+        Staleness { stale_after: Duration::from_secs(120), no_data_after: Duration::from_secs(600) }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    Fresh,
+    Stale,
+    NoData,
 }
 
 // represents the state of a single tram line, which can have multiple directions (towards both directions of the line)
 #[derive(Debug)]
-pub struct TramLineState {
-    pub line: heapless::String<16>, // Display name of the line, e.g. "Tram C"
-    pub directions: heapless::Vec<TramDirectionState, 2>, // for now we assume that there are at most 2 directions per line, but this can be easily changed if needed
+pub struct TramLineState<const DIRECTIONS: usize = DEFAULT_DIRECTIONS, const PASSAGES: usize = DEFAULT_PASSAGES> {
+    pub id: LineId, // id of the line in the MQTT topics, e.g. "C"
+    pub line: LineName, // Display name of the line, e.g. "Tram C"
+    pub directions: heapless::Vec<TramDirectionState<PASSAGES>, DIRECTIONS>,
+}
+
+impl<const DIRECTIONS: usize, const PASSAGES: usize> TramLineState<DIRECTIONS, PASSAGES> {
+    // a line is kept as long as its most recently updated direction
+    fn eviction_key(&self, now: Instant) -> (bool, Instant) {
+        self.directions.iter().map(|d| d.eviction_key(now)).max().unwrap_or((false, Instant::from_ticks(0)))
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub struct TramDirectionState {
-    pub update_at: heapless::String<10>, // timestamp of the last update, used to display the freshness of the data
+pub struct TramDirectionState<const PASSAGES: usize = DEFAULT_PASSAGES> {
+    pub update_at: UpdateAt, // timestamp of the last update, used to display the freshness of the data
     pub direction_id: usize, // id of the direction, uncoupled from the index in the `directions` vector (e.g: tramway in grenoble used 1 and 2 as direction_id) could be upgraded to a string if needed
-    pub next_passages: heapless::Vec<TramNextPassage, 3>,  // list of the next passages for this direction, we assume that there are at most 3 passages to display
+    pub next_passages: heapless::Vec<TramNextPassage, PASSAGES>,  // list of the next passages for this direction, the backend sends the first ones
+    pub computed_at: Instant, // when the backend computed the update (see `computed_at`), `relative_arrival` of the passages is relative to this instant
+    pub staleness: Staleness, // per direction, e.g. a night bus line published less often could tolerate older data
+    pub disruption: Option<Disruption>, // traffic info from the backend (works, diversion...), shown instead of the update time while the data is fresh
+}
+
+impl<const PASSAGES: usize> TramDirectionState<PASSAGES> {
+    pub fn freshness(&self, now: Instant) -> Freshness {
+        let age = now.checked_duration_since(self.computed_at).unwrap_or(Duration::from_ticks(0));
+        if age >= self.staleness.no_data_after {
+            Freshness::NoData
+        } else if age >= self.staleness.stale_after {
+            Freshness::Stale
+        } else {
+            Freshness::Fresh
+        }
+    }
+
+    // when a new line or direction doesn't fit, the one with the smallest key makes room for it:
+    // directions without data go first whatever their own thresholds, then the one that has gone without update the longest
+    fn eviction_key(&self, now: Instant) -> (bool, Instant) {
+        (self.freshness(now) != Freshness::NoData, self.computed_at)
+    }
+
+    // passages that haven't departed yet at `now`, along with their remaining minutes
+    // so the screen keeps counting down even if the backend stops publishing
+    // with a clock, the absolute arrivals are used: they don't depend on when the message was sent, so a retained
+    // message or a slow network doesn't shift them; without it, or for a passage without one, the relative minutes
+    pub fn remaining_passages(&self, now: Instant, clock: Option<&WallClock>) -> impl Iterator<Item = (&TramNextPassage, u8)> {
+        let elapsed = now.checked_duration_since(self.computed_at).unwrap_or(Duration::from_ticks(0));
+        let elapsed_minutes = elapsed.as_secs() / 60;
+        let unix_secs = clock.map(|clock| clock.unix_secs(now));
+        self.next_passages.iter().filter_map(move |passage| {
+            let remaining = match (passage.arrival_at, unix_secs) {
+                (Some(arrival_at), Some(unix_secs)) => arrival_at.checked_sub(unix_secs)? / 60,
+                _ => (passage.relative_arrival as u64).checked_sub(elapsed_minutes)?,
+            };
+            Some((passage, remaining.min(u8::MAX as u64) as u8))
+        })
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct TramNextPassage {
-    pub destination: heapless::String<32>, // display name of the destination of the tram, e.g. "Gare"
-    pub relative_arrival: u8 // relative arrival time in minutes, used to display the time until the next tram arrives
+    pub destination: Destination, // display name of the destination of the tram, e.g. "Gare"
+    pub relative_arrival: u8, // relative arrival time in minutes, used to display the time until the next tram arrives
+    pub arrival_at: Option<u64>, // absolute arrival time (unix seconds, UTC) if the backend sends it, preferred once the device has a clock
+    pub arrival_kind: ArrivalKind, // whether the arrival time is a live estimate or comes from the timetable
+}
+
+// `R` or `S` flag of the payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArrivalKind {
+    Realtime, // live estimate computed from the tram position
+    Scheduled, // timetable time, the tram may not even be on its way yet
+}
+
+// a new line or direction didn't fit in the state, the one updated the longest ago was dropped to make room for it
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateOverflow {
+    Lines { dropped: LineId },
+    Directions { line_id: LineId, dropped: usize }, // `dropped` is the direction id
 }
 
 // trait that defines the interface for rendering the UI state, which can be implemented by different display types (e.g. LCD, OLED, etc.)
 pub trait TramDisplay {
-    fn render<'a>(&'a mut self, state: &'a UiState) -> impl core::future::Future<Output = ()> + 'a;
+    fn render<'a, const LINES: usize, const DIRECTIONS: usize, const PASSAGES: usize>(
+        &'a mut self,
+        state: &'a UiState<LINES, DIRECTIONS, PASSAGES>,
+    ) -> impl core::future::Future<Output = ()> + 'a;
     fn healthcheck<'a>(&'a mut self) -> impl core::future::Future<Output = ()> + 'a;
 }
 
 // When we receive a ui command, we need to update the UI state accordingly, this function contains the logic to do so
-pub fn apply_ui_command(state: &mut UiState, cmd: UiCommand) {
+// `now` is the time at which the command was received
+// the command is always applied, a line or direction that had to be dropped to make room for it is reported
+pub fn apply_ui_command<const LINES: usize, const DIRECTIONS: usize, const PASSAGES: usize>(
+    state: &mut UiState<LINES, DIRECTIONS, PASSAGES>,
+    cmd: UiCommand<PASSAGES>,
+    now: Instant,
+) -> Option<StateOverflow> {
+    state.now = now;
     match cmd {
-        UiCommand::UpdateDirection { line, direction_id, next_passages, update_at } => {
-            if let Some(line_state) = state.lines.iter_mut().find(|l| l.line == line) {
-                if let Some(dir_state) = line_state
-                    .directions
-                        .iter_mut()
-                        .find(|d| d.direction_id == direction_id)
-                {
-                    // update the existing direction state with the new passages and update_at timestamp
-
-                    // we assume the backend already sorted the passages by arrival time
-                    dir_state.next_passages = next_passages;
-                    dir_state.update_at = update_at;
-                } else {
-                    let _ = line_state.directions.push(
-                        TramDirectionState {
-                            update_at,
-                            direction_id,
-                            next_passages
-                        }
-                    );
-                }
-            } else {
-                // if the line doesn't exist yet in the state, we create a new line state and add it to the list of lines
-                let mut new_line = TramLineState {
-                    line,
-                    directions: heapless::Vec::new()
-                };
-                let _ = new_line.directions.push(
-                    TramDirectionState {
-                        update_at,
-                        direction_id,
-                        next_passages,
+        UiCommand::UpdateDirection { line_id, line, direction_id, next_passages, update_at, disruption, staleness } => {
+            let mut overflow = None;
+            let staleness = staleness.unwrap_or(state.staleness);
+            let computed_at = computed_at(&update_at, state.clock.as_ref(), now);
+            // the boot messages are no longer needed
+            if state.current_message.as_ref().is_some_and(|m| m.severity == Severity::Info) {
+                state.current_message = None;
+            }
+            let line_index = match state.lines.iter().position(|l| l.id == line_id) {
+                Some(index) => index,
+                None => {
+                    // if the line doesn't exist yet in the state, we create a new line state and add it to the list of lines
+                    if state.lines.is_full() {
+                        let oldest = (0..state.lines.len()).min_by_key(|&i| state.lines[i].eviction_key(now)).unwrap_or(0);
+                        log!("No room for line {}, removing line {}", line_id, state.lines[oldest].id);
+                        overflow = Some(StateOverflow::Lines { dropped: state.lines[oldest].id.clone() });
+                        state.remove_line(oldest);
+                    }
+                    // there is room now
+                    let rank = line_rank(state.line_order, &line_id);
+                    let index = state.lines.iter().position(|l| line_rank(state.line_order, &l.id) > rank).unwrap_or(state.lines.len());
+                    let _ = state.lines.insert(index, TramLineState { id: line_id, line: heapless::String::new(), directions: heapless::Vec::new() });
+                    index
                 }
-                );
+            };
 
-                let _ = state.lines.push(new_line);
-            }
-        },
-        UiCommand::NextScreen => {
-            // in the current implementation, this is controlled by a button, could also be a timer to automatically cycle through the screens
+            let line_state = &mut state.lines[line_index];
+            // the display name can be changed by the backend at any time
+            line_state.line = line;
+            if let Some(dir_state) = line_state
+                .directions
+                    .iter_mut()
+                    .find(|d| d.direction_id == direction_id)
+            {
+                // update the existing direction state with the new passages and update_at timestamp
 
-            let lines = &state.lines;
-            if lines.is_empty() {
-                return;
+                // we assume the backend already sorted the passages by arrival time
+                dir_state.next_passages = next_passages;
+                dir_state.update_at = update_at;
+                dir_state.computed_at = computed_at;
+                dir_state.disruption = disruption;
+                dir_state.staleness = staleness;
+                return overflow;
             }
 
-            state.current_direction_id += 1;
-
-            let line = &lines[state.current_line];
-            if state.current_direction_id >= line.directions.len() {
-                state.current_direction_id = 0;
-                state.current_line = (state.current_line + 1) % lines.len();
+            if line_state.directions.is_full() {
+                let directions = &line_state.directions;
+                let oldest = (0..directions.len()).min_by_key(|&i| directions[i].eviction_key(now)).unwrap_or(0);
+                log!("No room for direction {} of line {}, removing direction {}", direction_id, line_state.id, directions[oldest].direction_id);
+                overflow = Some(StateOverflow::Directions { line_id: line_state.id.clone(), dropped: directions[oldest].direction_id });
+                state.remove_direction(line_index, oldest);
+            }
+            let directions = &mut state.lines[line_index].directions;
+            let index = directions.iter().position(|d| d.direction_id > direction_id).unwrap_or(directions.len());
+            let _ = directions.insert(
+                index,
+                TramDirectionState {
+                    update_at,
+                    direction_id,
+                    next_passages,
+                    computed_at,
+                    staleness,
+                    disruption,
+                }
+            );
+            // the first screen received stays on until something else is chosen
+            if state.selected.is_none() {
+                state.select_position(line_index, index);
+            }
+            return overflow;
+        },
+        UiCommand::RemoveDirection { line_id, direction_id } => {
+            let line = state.lines.iter().position(|l| l.id == line_id)?;
+            let direction = state.lines[line].directions.iter().position(|d| d.direction_id == direction_id)?;
+            state.remove_direction(line, direction);
+            if state.lines[line].directions.is_empty() {
+                state.remove_line(line);
+            }
+        },
+        UiCommand::RemoveLine { line_id } => {
+            let line = state.lines.iter().position(|l| l.id == line_id)?;
+            state.remove_line(line);
+        },
+        UiCommand::NextScreen => {
+            // manual change (button), the automatic rotation leaves it on the screen for a while
+            state.next_screen();
+            state.next_rotation_at = now + state.rotation.pause_after_press;
+        },
+        UiCommand::PreviousScreen => {
+            state.previous_screen();
+            state.next_rotation_at = now + state.rotation.pause_after_press;
+        },
+        UiCommand::ShowDirection { line_id, direction_id } => {
+            let line = state.lines.iter().find(|l| l.id == line_id)?;
+            if !line.directions.iter().any(|d| d.direction_id == direction_id) {
+                return None;
             }
+            state.selected = Some(ScreenKey { line_id, direction_id });
+            state.clock_selected = false;
+            state.next_rotation_at = now + state.rotation.pause_after_press;
         },
-        UiCommand::UpdateMessage(string_inner) => {
-            state.current_message = Some(string_inner);
+        UiCommand::ShowClock => {
+            // nothing to show until the device has a clock
+            state.clock?;
+            state.clock_selected = true;
+            state.next_rotation_at = now + state.rotation.pause_after_press;
+        },
+        UiCommand::SetPinned(pinned) => {
+            state.pinned = pinned;
+            state.next_rotation_at = now + state.rotation.dwell;
+        },
+        UiCommand::TogglePinned => {
+            state.pinned = !state.pinned;
+            state.next_rotation_at = now + state.rotation.dwell;
+        },
+        UiCommand::UpdateMessage { text, severity, expires_after } => {
+            // a less severe message doesn't hide the current one, e.g. a boot step doesn't replace a connection error
+            let current = state.current_message.as_ref().filter(|m| !m.expired(now));
+            if current.is_none_or(|current| severity >= current.severity) {
+                state.current_message = Some(StatusMessage { text, severity, expires_at: expires_after.map(|after| now + after) });
+            }
+        }
+        UiCommand::ClearMessage { text } => {
+            if state.current_message.as_ref().is_some_and(|current| current.text == text) {
+                state.current_message = None;
+            }
         }
         UiCommand::ToggleBacklight => {
             state.backlight_on = !state.backlight_on;
         },
         UiCommand::SetBacklight(on) => {
             state.backlight_on = on;
+        },
+        UiCommand::SetAutoRotation(enabled) => {
+            state.rotation.enabled = enabled;
+            state.next_rotation_at = now + state.rotation.dwell;
+        },
+        UiCommand::SetRotationDwell(dwell) => {
+            state.rotation.dwell = dwell;
+            state.next_rotation_at = now + dwell;
+        },
+        UiCommand::SetNightMode(on) => {
+            state.night_mode = on;
+        }
+        UiCommand::SetTime { unix_secs } => {
+            state.clock = Some(WallClock { unix_secs, set_at: now });
+        }
+        UiCommand::Tick => {
+            if state.current_message.as_ref().is_some_and(|m| m.expired(now)) {
+                state.current_message = None;
+            }
+            // `now` is already updated above, the freshness of the directions is derived from it
+            // the rotation is only as precise as the render tick
+            if state.rotation.enabled && !state.pinned && now >= state.next_rotation_at {
+                state.next_screen();
+                state.next_rotation_at = now + state.rotation.dwell;
+            }
         }
     }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn passage(destination: &str, relative_arrival: u8) -> TramNextPassage {
+        TramNextPassage {
+            destination: heapless::String::try_from(destination).unwrap(),
+            relative_arrival,
+            arrival_at: None,
+            arrival_kind: ArrivalKind::Realtime,
+        }
+    }
+
+    fn direction(received_at: Instant) -> TramDirectionState {
+        let mut next_passages = heapless::Vec::new();
+        let _ = next_passages.push(passage("Gare", 0));
+        let _ = next_passages.push(passage("Plaine des sports", 3));
+        TramDirectionState {
+            update_at: heapless::String::try_from("14:35:10").unwrap(),
+            direction_id: 1,
+            next_passages,
+            computed_at: received_at,
+            staleness: Staleness::default(),
+            disruption: None,
+        }
+    }
+
+    fn remaining(state: &TramDirectionState, now: Instant) -> heapless::Vec<u8, 3> {
+        state.remaining_passages(now, None).map(|(_, minutes)| minutes).collect()
+    }
+
+    #[test]
+    fn counts_down_between_updates() {
+        let received_at = Instant::from_secs(1000);
+        let state = direction(received_at);
+
+        assert_eq!(remaining(&state, received_at), [0, 3]);
+        assert_eq!(remaining(&state, received_at + Duration::from_secs(59)), [0, 3]);
+        // the first tram is gone once a full minute has passed
+        assert_eq!(remaining(&state, received_at + Duration::from_secs(60)), [2]);
+        assert_eq!(remaining(&state, received_at + Duration::from_secs(3 * 60)), [0]);
+        assert!(remaining(&state, received_at + Duration::from_secs(4 * 60)).is_empty());
+    }
+
+    #[test]
+    fn flags_old_data() {
+        let received_at = Instant::from_secs(1000);
+        let state = direction(received_at);
+
+        assert_eq!(state.freshness(received_at + Duration::from_secs(119)), Freshness::Fresh);
+        assert_eq!(state.freshness(received_at + Duration::from_secs(120)), Freshness::Stale);
+        assert_eq!(state.freshness(received_at + Duration::from_secs(600)), Freshness::NoData);
+    }
+
+    #[test]
+    fn clock_before_reception_does_not_count_down() {
+        let state = direction(Instant::from_secs(1000));
+        assert_eq!(remaining(&state, Instant::from_secs(10)), [0, 3]);
+    }
+
+    #[test]
+    fn absolute_arrivals_need_a_clock() {
+        // retained message received long after it was sent: 3 and 8 minutes when it was published, at 14:30 UTC
+        let received_at = Instant::from_secs(1000);
+        let mut state = direction(received_at);
+        let published_at = 1_760_623_800;
+        state.next_passages[0].relative_arrival = 3;
+        state.next_passages[0].arrival_at = Some(published_at + 3 * 60);
+        state.next_passages[1].relative_arrival = 8;
+        state.next_passages[1].arrival_at = Some(published_at + 8 * 60);
+
+        // without a clock, the relative minutes count from the reception
+        assert_eq!(remaining(&state, received_at), [3, 8]);
+
+        // the clock says it's now 14:35:30 UTC
+        let clock = WallClock { unix_secs: published_at + 5 * 60 + 30, set_at: received_at };
+        let with_clock = |now: Instant| -> heapless::Vec<u8, 3> {
+            state.remaining_passages(now, Some(&clock)).map(|(_, minutes)| minutes).collect()
+        };
+        assert_eq!(with_clock(received_at), [2]);
+        assert_eq!(with_clock(received_at + Duration::from_secs(30)), [2]);
+        assert_eq!(with_clock(received_at + Duration::from_secs(90)), [1]);
+
+        // the passages without an absolute time still use the relative minutes
+        state.next_passages[1].arrival_at = None;
+        let with_clock = |now: Instant| -> heapless::Vec<u8, 3> {
+            state.remaining_passages(now, Some(&clock)).map(|(_, minutes)| minutes).collect()
+        };
+        assert_eq!(with_clock(received_at + Duration::from_secs(60)), [7]);
+    }
+
+    #[test]
+    fn set_time_starts_the_clock() {
+        let mut state = state_with_screens();
+        assert!(state.clock.is_none());
+        apply_ui_command(&mut state, UiCommand::SetTime { unix_secs: 1_760_623_800 }, Instant::from_secs(100));
+        let clock = state.clock.unwrap();
+        assert_eq!(clock.unix_secs(Instant::from_secs(100)), 1_760_623_800);
+        assert_eq!(clock.unix_secs(Instant::from_secs(160)), 1_760_623_860);
+    }
+
+    #[test]
+    fn data_age_comes_from_the_update_time_once_the_clock_is_set() {
+        let mut state = state_with_screens();
+        // 2025-10-16 14:38:00 in Paris
+        apply_ui_command(&mut state, UiCommand::SetTime { unix_secs: 1_760_618_280 }, Instant::from_secs(1000));
+
+        // retained message computed at 14:35:10, received almost 3 minutes later
+        update_at(&mut state, "C", 1, 1000);
+        let direction = &state.lines[1].directions[0];
+        assert_eq!(direction.computed_at, Instant::from_secs(1000 - 170));
+        assert_eq!(direction.freshness(Instant::from_secs(1000)), Freshness::Stale);
+
+        // the backend clock a bit ahead of ours, at 14:35:00
+        apply_ui_command(&mut state, UiCommand::SetTime { unix_secs: 1_760_618_280 - 3 * 60 }, Instant::from_secs(1000));
+        update_at(&mut state, "C", 2, 1000);
+        assert_eq!(state.lines[1].directions[1].computed_at, Instant::from_secs(1000));
+
+        // not a time, back to the reception
+        let update = UiCommand::UpdateDirection {
+            line_id: LineId::try_from("A").unwrap(),
+            line: LineName::try_from("Tram A").unwrap(),
+            direction_id: 1,
+            next_passages: heapless::Vec::new(),
+            update_at: UpdateAt::try_from("maintenant").unwrap(),
+            disruption: None,
+            staleness: None,
+        };
+        apply_ui_command(&mut state, update, Instant::from_secs(1000));
+        assert_eq!(state.lines[0].directions[0].computed_at, Instant::from_secs(1000));
+
+        assert_eq!(state.local_time().map(|t| (t.hour, t.minute)), Some((14, 35)));
+    }
+
+    #[test]
+    fn clock_joins_the_rotation() {
+        let mut state = state_with_screens();
+        state.clock_in_rotation = true;
+        apply_ui_command(&mut state, UiCommand::SetAutoRotation(true), Instant::from_secs(0));
+        // not before the device has a clock
+        apply_ui_command(&mut state, UiCommand::ShowClock, Instant::from_secs(0));
+        show(&mut state, "C", 2, 0);
+        apply_ui_command(&mut state, UiCommand::NextScreen, Instant::from_secs(0));
+        assert_eq!(screen(&state), ("A", 1));
+
+        apply_ui_command(&mut state, UiCommand::SetTime { unix_secs: 1_760_618_280 }, Instant::from_secs(0));
+        assert!(state.clock_screen().is_none());
+        apply_ui_command(&mut state, UiCommand::PreviousScreen, Instant::from_secs(0));
+        assert!(state.clock_selected);
+        assert_eq!(state.clock_screen().map(|t| (t.hour, t.minute)), Some((14, 38)));
+        apply_ui_command(&mut state, UiCommand::PreviousScreen, Instant::from_secs(0));
+        assert_eq!(screen(&state), ("C", 2));
+
+        tick_at(&mut state, 60);
+        assert!(state.clock_selected);
+        tick_at(&mut state, 70);
+        assert_eq!((state.clock_selected, screen(&state)), (false, ("A", 1)));
+
+        apply_ui_command(&mut state, UiCommand::ShowClock, Instant::from_secs(70));
+        assert!(state.clock_selected);
+        show(&mut state, "C", 1, 70);
+        assert!(!state.clock_selected);
+    }
+
+    #[test]
+    fn clock_replaces_the_boot_messages_and_the_passages_at_night() {
+        let mut state: UiState = UiState::new(Staleness::default(), Instant::from_secs(0));
+        apply_ui_command(&mut state, message(Severity::Info, "Connected to MQTT server !", None), Instant::from_secs(0));
+        apply_ui_command(&mut state, UiCommand::SetTime { unix_secs: 1_760_618_280 }, Instant::from_secs(0));
+        assert_eq!(visible(&state), None);
+        assert!(state.clock_screen().is_some());
+        apply_ui_command(&mut state, message(Severity::Warning, "Wifi lent", None), Instant::from_secs(0));
+        assert_eq!(visible(&state), Some(("Wifi lent", MessagePlacement::Banner)));
+
+        update_at(&mut state, "C", 1, 1);
+        assert!(state.clock_screen().is_none());
+        apply_ui_command(&mut state, UiCommand::SetNightMode(true), Instant::from_secs(2));
+        assert!(state.clock_screen().is_some());
+        apply_ui_command(&mut state, UiCommand::SetNightMode(false), Instant::from_secs(3));
+        assert!(state.clock_screen().is_none());
+    }
+
+    // state with lines A and C, 2 directions each
+    fn state_with_screens() -> UiState {
+        let mut state = UiState::new(Staleness::default(), Instant::from_secs(0));
+        for (line_id, line) in [("A", "Tram A"), ("C", "Tram C")] {
+            for direction_id in [1, 2] {
+                let update = UiCommand::UpdateDirection {
+                    line_id: heapless::String::try_from(line_id).unwrap(),
+                    line: heapless::String::try_from(line).unwrap(),
+                    direction_id,
+                    next_passages: heapless::Vec::new(),
+                    update_at: heapless::String::try_from("14:35:10").unwrap(),
+                    disruption: None,
+                    staleness: None,
+                };
+                apply_ui_command(&mut state, update, Instant::from_secs(0));
+            }
+        }
+        state
+    }
+
+    fn screen(state: &UiState) -> (&str, usize) {
+        let (line, direction) = state.current_screen().unwrap();
+        (line.id.as_str(), direction.direction_id)
+    }
+
+    fn tick_at(state: &mut UiState, secs: u64) {
+        apply_ui_command(state, UiCommand::Tick, Instant::from_secs(secs));
+    }
+
+    #[test]
+    fn rotation_cycles_through_all_screens() {
+        let mut state = state_with_screens();
+        apply_ui_command(&mut state, UiCommand::SetAutoRotation(true), Instant::from_secs(0));
+
+        tick_at(&mut state, 5);
+        assert_eq!(screen(&state), ("A", 1));
+        tick_at(&mut state, 10);
+        assert_eq!(screen(&state), ("A", 2));
+        tick_at(&mut state, 20);
+        assert_eq!(screen(&state), ("C", 1));
+        tick_at(&mut state, 30);
+        assert_eq!(screen(&state), ("C", 2));
+        tick_at(&mut state, 40);
+        assert_eq!(screen(&state), ("A", 1));
+
+        apply_ui_command(&mut state, UiCommand::SetRotationDwell(Duration::from_secs(30)), Instant::from_secs(40));
+        tick_at(&mut state, 60);
+        assert_eq!(screen(&state), ("A", 1));
+        tick_at(&mut state, 70);
+        assert_eq!(screen(&state), ("A", 2));
+    }
+
+    #[test]
+    fn rotation_waits_after_a_button_press() {
+        let mut state = state_with_screens();
+        apply_ui_command(&mut state, UiCommand::SetAutoRotation(true), Instant::from_secs(0));
+
+        apply_ui_command(&mut state, UiCommand::NextScreen, Instant::from_secs(3));
+        assert_eq!(screen(&state), ("A", 2));
+        tick_at(&mut state, 60);
+        assert_eq!(screen(&state), ("A", 2));
+        tick_at(&mut state, 63);
+        assert_eq!(screen(&state), ("C", 1));
+    }
+
+    fn show(state: &mut UiState, line_id: &str, direction_id: usize, secs: u64) {
+        let cmd = UiCommand::ShowDirection { line_id: heapless::String::try_from(line_id).unwrap(), direction_id };
+        apply_ui_command(state, cmd, Instant::from_secs(secs));
+    }
+
+    #[test]
+    fn navigates_backwards() {
+        let mut state = state_with_screens();
+        for expected in [("C", 2), ("C", 1), ("A", 2), ("A", 1), ("C", 2)] {
+            apply_ui_command(&mut state, UiCommand::PreviousScreen, Instant::from_secs(0));
+            assert_eq!(screen(&state), expected);
+        }
+    }
+
+    #[test]
+    fn shows_a_given_direction() {
+        let mut state = state_with_screens();
+        show(&mut state, "C", 2, 0);
+        assert_eq!(screen(&state), ("C", 2));
+        show(&mut state, "A", 1, 0);
+        assert_eq!(screen(&state), ("A", 1));
+
+        // unknown lines and directions are ignored
+        show(&mut state, "C", 3, 0);
+        show(&mut state, "E", 1, 0);
+        assert_eq!(screen(&state), ("A", 1));
+    }
+
+    #[test]
+    fn pinned_screen_stays() {
+        let mut state = state_with_screens();
+        apply_ui_command(&mut state, UiCommand::SetAutoRotation(true), Instant::from_secs(0));
+        show(&mut state, "C", 1, 0);
+        apply_ui_command(&mut state, UiCommand::SetPinned(true), Instant::from_secs(0));
+        tick_at(&mut state, 600);
+        assert_eq!(screen(&state), ("C", 1));
+
+        // the button still works
+        apply_ui_command(&mut state, UiCommand::NextScreen, Instant::from_secs(600));
+        assert_eq!(screen(&state), ("C", 2));
+
+        apply_ui_command(&mut state, UiCommand::TogglePinned, Instant::from_secs(700));
+        assert!(!state.pinned);
+        tick_at(&mut state, 705);
+        assert_eq!(screen(&state), ("C", 2));
+        tick_at(&mut state, 710);
+        assert_eq!(screen(&state), ("A", 1));
+    }
+
+    #[test]
+    fn updates_are_matched_by_line_id() {
+        let mut state = state_with_screens();
+        let update = UiCommand::UpdateDirection {
+            line_id: heapless::String::try_from("C").unwrap(),
+            line: heapless::String::try_from("Tram C (travaux)").unwrap(),
+            direction_id: 1,
+            next_passages: heapless::Vec::new(),
+            update_at: heapless::String::try_from("14:36:10").unwrap(),
+            disruption: Some(heapless::String::try_from("Arret Gares non desservi").unwrap()),
+            staleness: None,
+        };
+        apply_ui_command(&mut state, update, Instant::from_secs(60));
+        assert_eq!(state.lines.len(), 2);
+        assert_eq!(state.lines[1].line, "Tram C (travaux)");
+        assert_eq!(state.lines[1].directions[0].update_at, "14:36:10");
+        assert_eq!(state.lines[1].directions[0].disruption.as_deref(), Some("Arret Gares non desservi"));
+        // each update replaces the disruption, an update without it means it's over
+        update_at(&mut state, "C", 1, 120);
+        assert!(state.lines[1].directions[0].disruption.is_none());
+    }
+
+    #[test]
+    fn rotation_is_disabled_by_default() {
+        let mut state = state_with_screens();
+        tick_at(&mut state, 600);
+        assert_eq!(screen(&state), ("A", 1));
+
+        apply_ui_command(&mut state, UiCommand::SetAutoRotation(true), Instant::from_secs(600));
+        apply_ui_command(&mut state, UiCommand::SetAutoRotation(false), Instant::from_secs(605));
+        tick_at(&mut state, 700);
+        assert_eq!(screen(&state), ("A", 1));
+    }
+
+    fn update_at(state: &mut UiState, line_id: &str, direction_id: usize, secs: u64) -> Option<StateOverflow> {
+        let update = UiCommand::UpdateDirection {
+            line_id: heapless::String::try_from(line_id).unwrap(),
+            line: heapless::String::try_from(line_id).unwrap(),
+            direction_id,
+            next_passages: heapless::Vec::new(),
+            update_at: heapless::String::try_from("14:35:10").unwrap(),
+            disruption: None,
+            staleness: None,
+        };
+        apply_ui_command(state, update, Instant::from_secs(secs))
+    }
+
+    fn remove_direction_cmd(line_id: &str, direction_id: usize) -> UiCommand {
+        UiCommand::RemoveDirection { line_id: heapless::String::try_from(line_id).unwrap(), direction_id }
+    }
+
+    fn screens(state: &UiState) -> std::vec::Vec<(&str, usize)> {
+        state.lines.iter().flat_map(|l| l.directions.iter().map(|d| (l.id.as_str(), d.direction_id))).collect()
+    }
+
+    #[test]
+    fn removes_directions_and_lines() {
+        let mut state = state_with_screens();
+        show(&mut state, "C", 2, 0);
+
+        apply_ui_command(&mut state, remove_direction_cmd("A", 2), Instant::from_secs(0));
+        assert_eq!(screens(&state), [("A", 1), ("C", 1), ("C", 2)]);
+        assert_eq!(screen(&state), ("C", 2));
+
+        // the line goes away with its last direction
+        apply_ui_command(&mut state, remove_direction_cmd("A", 1), Instant::from_secs(0));
+        assert_eq!(screens(&state), [("C", 1), ("C", 2)]);
+        assert_eq!(screen(&state), ("C", 2));
+
+        // unknown lines and directions are ignored
+        apply_ui_command(&mut state, remove_direction_cmd("C", 3), Instant::from_secs(0));
+        apply_ui_command(&mut state, UiCommand::RemoveLine { line_id: heapless::String::try_from("E").unwrap() }, Instant::from_secs(0));
+        assert_eq!(screens(&state), [("C", 1), ("C", 2)]);
+
+        apply_ui_command(&mut state, UiCommand::RemoveLine { line_id: heapless::String::try_from("C").unwrap() }, Instant::from_secs(0));
+        assert!(state.lines.is_empty());
+        assert!(state.current_screen().is_none());
+        // nothing to show, but the navigation doesn't break
+        apply_ui_command(&mut state, UiCommand::NextScreen, Instant::from_secs(0));
+        apply_ui_command(&mut state, UiCommand::PreviousScreen, Instant::from_secs(0));
+        assert!(state.current_screen().is_none());
+
+        // the next screen received is shown
+        update_at(&mut state, "E", 2, 10);
+        assert_eq!(screen(&state), ("E", 2));
+    }
+
+    #[test]
+    fn removing_the_shown_screen_unpins_it() {
+        let mut state = state_with_screens();
+        show(&mut state, "C", 2, 0);
+        apply_ui_command(&mut state, UiCommand::SetPinned(true), Instant::from_secs(0));
+
+        apply_ui_command(&mut state, remove_direction_cmd("C", 2), Instant::from_secs(0));
+        assert!(!state.pinned);
+        assert_eq!(screen(&state), ("C", 1));
+
+        // last line removed, back to the first one
+        apply_ui_command(&mut state, remove_direction_cmd("C", 1), Instant::from_secs(0));
+        assert_eq!(screen(&state), ("A", 1));
+        assert_eq!(screens(&state), [("A", 1), ("A", 2)]);
+    }
+
+    #[test]
+    fn evicts_the_line_updated_the_longest_ago() {
+        let mut state = state_with_screens();
+        for (i, line_id) in ["B", "D", "E", "F", "G", "H"].iter().enumerate() {
+            assert_eq!(update_at(&mut state, line_id, 1, 10 + i as u64), None);
+        }
+        show(&mut state, "D", 1, 20);
+        // A and C were received at 0, C is refreshed
+        update_at(&mut state, "C", 1, 30);
+
+        let dropped = heapless::String::try_from("A").unwrap();
+        assert_eq!(update_at(&mut state, "T", 1, 40), Some(StateOverflow::Lines { dropped }));
+        assert_eq!(state.lines.len(), 8);
+        assert!(state.lines.iter().all(|l| l.id != "A"));
+        assert_eq!(state.lines.last().unwrap().id, "T");
+        assert_eq!(screen(&state), ("D", 1));
+
+        // lines without data go first, even if another one was updated before them
+        // D is published rarely, its updates tell it's without data after 5 seconds (received at 11)
+        let update = UiCommand::UpdateDirection {
+            line_id: heapless::String::try_from("D").unwrap(),
+            line: heapless::String::try_from("D").unwrap(),
+            direction_id: 1,
+            next_passages: heapless::Vec::new(),
+            update_at: heapless::String::try_from("14:35:10").unwrap(),
+            disruption: None,
+            staleness: Some(Staleness { stale_after: Duration::from_secs(2), no_data_after: Duration::from_secs(5) }),
+        };
+        apply_ui_command(&mut state, update, Instant::from_secs(11));
+        update_at(&mut state, "U", 1, 41);
+        assert!(state.lines.iter().all(|l| l.id != "D"));
+        assert!(state.lines.iter().any(|l| l.id == "B"));
+        // the line taking its place is shown
+        assert_eq!(screen(&state), ("E", 1));
+    }
+
+    #[test]
+    fn directions_can_have_their_own_staleness() {
+        let mut state = state_with_screens();
+        let night_bus = Staleness { stale_after: Duration::from_secs(1800), no_data_after: Duration::from_secs(3600) };
+        let update = |staleness| UiCommand::UpdateDirection {
+            line_id: heapless::String::try_from("N1").unwrap(),
+            line: heapless::String::try_from("Noctibus").unwrap(),
+            direction_id: 1,
+            next_passages: heapless::Vec::new(),
+            update_at: heapless::String::try_from("02:35:10").unwrap(),
+            disruption: None,
+            staleness,
+        };
+        apply_ui_command(&mut state, update(Some(night_bus)), Instant::from_secs(0));
+        let n1 = state.lines.iter().position(|l| l.id == "N1").unwrap();
+        assert_eq!(state.lines[n1].directions[0].freshness(Instant::from_secs(600)), Freshness::Fresh);
+        // the other directions keep the default thresholds
+        assert_eq!(state.lines[0].directions[0].freshness(Instant::from_secs(600)), Freshness::NoData);
+
+        // like the disruption, each update gives them again, back to the default without them
+        apply_ui_command(&mut state, update(None), Instant::from_secs(0));
+        assert_eq!(state.lines[n1].directions[0].staleness, state.staleness);
+    }
+
+    #[test]
+    fn evicts_the_direction_updated_the_longest_ago() {
+        let mut state = state_with_screens();
+        update_at(&mut state, "C", 1, 10);
+        show(&mut state, "C", 1, 10);
+
+        let line_id = heapless::String::try_from("C").unwrap();
+        assert_eq!(update_at(&mut state, "C", 3, 20), Some(StateOverflow::Directions { line_id, dropped: 2 }));
+        assert_eq!(screens(&state), [("A", 1), ("A", 2), ("C", 1), ("C", 3)]);
+        assert_eq!(screen(&state), ("C", 1));
+    }
+
+    #[test]
+    fn screens_are_sorted_whatever_the_arrival_order() {
+        let mut state = UiState::new(Staleness::default(), Instant::from_secs(0));
+        for (line_id, direction_id) in [("C11", 2), ("C2", 1), ("A", 2), ("C11", 1), ("A", 1), ("B", 1)] {
+            update_at(&mut state, line_id, direction_id, 0);
+        }
+        assert_eq!(screens(&state), [("A", 1), ("A", 2), ("B", 1), ("C2", 1), ("C11", 1), ("C11", 2)]);
+
+        let mut state = UiState::new(Staleness::default(), Instant::from_secs(0));
+        state.line_order = &["C2", "A"];
+        for (line_id, direction_id) in [("C11", 2), ("C2", 1), ("A", 2), ("B", 1)] {
+            update_at(&mut state, line_id, direction_id, 0);
+        }
+        assert_eq!(screens(&state), [("C2", 1), ("A", 2), ("B", 1), ("C11", 2)]);
+    }
+
+    #[test]
+    fn selection_follows_its_screen() {
+        let mut state = UiState::new(Staleness::default(), Instant::from_secs(0));
+        // the first screen received is shown, even if others come before it
+        update_at(&mut state, "C", 2, 0);
+        update_at(&mut state, "A", 1, 0);
+        update_at(&mut state, "C", 1, 0);
+        assert_eq!(screens(&state), [("A", 1), ("C", 1), ("C", 2)]);
+        assert_eq!(screen(&state), ("C", 2));
+
+        // browsing while lines come and go
+        apply_ui_command(&mut state, UiCommand::PreviousScreen, Instant::from_secs(1));
+        assert_eq!(screen(&state), ("C", 1));
+        update_at(&mut state, "B", 1, 2);
+        assert_eq!(screen(&state), ("C", 1));
+        apply_ui_command(&mut state, UiCommand::PreviousScreen, Instant::from_secs(3));
+        assert_eq!(screen(&state), ("B", 1));
+        apply_ui_command(&mut state, remove_direction_cmd("A", 1), Instant::from_secs(4));
+        assert_eq!(screen(&state), ("B", 1));
+        apply_ui_command(&mut state, UiCommand::PreviousScreen, Instant::from_secs(5));
+        assert_eq!(screen(&state), ("C", 2));
+        update_at(&mut state, "A", 2, 6);
+        apply_ui_command(&mut state, UiCommand::NextScreen, Instant::from_secs(7));
+        assert_eq!(screen(&state), ("A", 2));
+
+        // a pinned screen stays pinned when other lines arrive
+        apply_ui_command(&mut state, UiCommand::SetPinned(true), Instant::from_secs(8));
+        apply_ui_command(&mut state, UiCommand::SetAutoRotation(true), Instant::from_secs(8));
+        update_at(&mut state, "0", 1, 9);
+        tick_at(&mut state, 100);
+        assert_eq!(screen(&state), ("A", 2));
+        assert!(state.pinned);
+    }
+
+    #[test]
+    fn capacities_can_be_raised() {
+        let mut state: UiState<16, 3, 3> = UiState::new(Staleness::default(), Instant::from_secs(0));
+        for line in 0..12 {
+            for direction_id in 1..=3 {
+                let update = UiCommand::UpdateDirection {
+                    line_id: LineId::try_from(line).unwrap(),
+                    line: LineName::try_from(line).unwrap(),
+                    direction_id,
+                    next_passages: heapless::Vec::new(),
+                    update_at: UpdateAt::try_from("14:35:10").unwrap(),
+                    disruption: None,
+                    staleness: None,
+                };
+                apply_ui_command(&mut state, update, Instant::from_secs(0));
+            }
+        }
+        assert_eq!(state.lines.len(), 12);
+        assert!(state.lines.iter().all(|l| l.directions.len() == 3));
+    }
+
+    fn message(severity: Severity, text: &str, expires_after: Option<u64>) -> UiCommand {
+        UiCommand::UpdateMessage {
+            text: Message::try_from(text).unwrap(),
+            severity,
+            expires_after: expires_after.map(Duration::from_secs),
+        }
+    }
+
+    fn visible(state: &UiState) -> Option<(&str, MessagePlacement)> {
+        state.visible_message().map(|(message, placement)| (message.text.as_str(), placement))
+    }
+
+    #[test]
+    fn boot_messages_go_away_with_the_data() {
+        let mut state: UiState = UiState::new(Staleness::default(), Instant::from_secs(0));
+        apply_ui_command(&mut state, message(Severity::Info, "Wifi connected !", None), Instant::from_secs(0));
+        assert_eq!(visible(&state), Some(("Wifi connected !", MessagePlacement::FullScreen)));
+
+        update_at(&mut state, "C", 1, 1);
+        assert!(state.current_message.is_none());
+        // later infos are kept but hidden behind the passages
+        apply_ui_command(&mut state, message(Severity::Info, "Connected to MQTT server !", None), Instant::from_secs(2));
+        assert_eq!(visible(&state), None);
+        apply_ui_command(&mut state, remove_direction_cmd("C", 1), Instant::from_secs(3));
+        assert_eq!(visible(&state), Some(("Connected to MQTT server !", MessagePlacement::FullScreen)));
+    }
+
+    #[test]
+    fn errors_take_over_the_screen() {
+        let mut state = state_with_screens();
+        apply_ui_command(&mut state, message(Severity::Warning, "Wifi lent", None), Instant::from_secs(0));
+        assert_eq!(visible(&state), Some(("Wifi lent", MessagePlacement::Banner)));
+        apply_ui_command(&mut state, message(Severity::Error, "Connexion MQTT perdue", None), Instant::from_secs(0));
+        assert_eq!(visible(&state), Some(("Connexion MQTT perdue", MessagePlacement::FullScreen)));
+
+        // the reconnection steps don't hide it, and the data doesn't clear it
+        apply_ui_command(&mut state, message(Severity::Info, "Waiting to get IP address...", None), Instant::from_secs(1));
+        update_at(&mut state, "C", 1, 2);
+        assert_eq!(visible(&state), Some(("Connexion MQTT perdue", MessagePlacement::FullScreen)));
+
+        // clearing another message leaves it alone
+        let clear = |text: &str| UiCommand::ClearMessage { text: heapless::String::try_from(text).unwrap() };
+        apply_ui_command(&mut state, clear("Wifi lent"), Instant::from_secs(3));
+        assert_eq!(visible(&state), Some(("Connexion MQTT perdue", MessagePlacement::FullScreen)));
+        apply_ui_command(&mut state, clear("Connexion MQTT perdue"), Instant::from_secs(3));
+        assert_eq!(visible(&state), None);
+
+        // nor a message that replaced it in the meantime
+        apply_ui_command(&mut state, message(Severity::Error, "Connexion MQTT perdue", None), Instant::from_secs(4));
+        apply_ui_command(&mut state, message(Severity::Error, "Trop de lignes", None), Instant::from_secs(5));
+        apply_ui_command(&mut state, clear("Connexion MQTT perdue"), Instant::from_secs(6));
+        assert_eq!(visible(&state), Some(("Trop de lignes", MessagePlacement::FullScreen)));
+    }
+
+    #[test]
+    fn messages_expire() {
+        let mut state = state_with_screens();
+        apply_ui_command(&mut state, message(Severity::Error, "Wifi: connexion impossible", Some(30)), Instant::from_secs(0));
+        tick_at(&mut state, 29);
+        assert!(visible(&state).is_some());
+        // hidden as soon as it expires, even before the tick drops it
+        state.now = Instant::from_secs(30);
+        assert_eq!(visible(&state), None);
+        apply_ui_command(&mut state, message(Severity::Warning, "Wifi lent", None), Instant::from_secs(30));
+        assert_eq!(visible(&state), Some(("Wifi lent", MessagePlacement::Banner)));
+
+        apply_ui_command(&mut state, message(Severity::Warning, "Mise a jour", Some(10)), Instant::from_secs(40));
+        tick_at(&mut state, 50);
+        assert!(state.current_message.is_none());
+    }
 }
@@ -0,0 +1,372 @@
+// 2.9" 296x128 black and white e-paper (SSD1680 controller over SPI, e.g. Waveshare 2.9" V2)
+// Refreshing e-paper is slow (~2s for a full refresh) and is where all the energy goes, so:
+// - the screen is only refreshed when the rendered framebuffer actually changed (same idea as the LCD display buffer)
+// - minute changes use a partial refresh (no flashing, ~0.3s)
+// - a full refresh is done every few updates to clear the ghosting left by the partial ones
+
+use crate::canvas::Canvas;
+
+pub const PANEL_WIDTH: usize = 128; // native orientation of the controller RAM, the panel is used in landscape
+pub const PANEL_HEIGHT: usize = 296;
+const ROW_BYTES: usize = PANEL_WIDTH / 8;
+pub const BUFFER_SIZE: usize = ROW_BYTES * PANEL_HEIGHT;
+
+// the layout is drawn with the same 5x7 font as the OLED, scaled x2 so it's readable from a distance
+const SCALE: u16 = 2;
+
+// controller RAM layout: one bit per pixel, 8 horizontal pixels per byte (MSB first), 1 is white
+// the canvas coordinates are in landscape (296x128 scaled down to 148x64) and rotated when written
+#[derive(Clone, PartialEq)]
+pub struct EpaperFramebuffer {
+    buffer: [u8; BUFFER_SIZE],
+}
+
+impl Default for EpaperFramebuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EpaperFramebuffer {
+    pub const fn new() -> Self {
+        EpaperFramebuffer { buffer: [0xFF; BUFFER_SIZE] }
+    }
+
+    pub fn as_bytes(&self) -> &[u8; BUFFER_SIZE] {
+        &self.buffer
+    }
+
+    // avoids a 4.7kB temporary on the stack when doing `*a = b.clone()`
+    pub fn copy_from(&mut self, other: &EpaperFramebuffer) {
+        self.buffer.copy_from_slice(&other.buffer);
+    }
+
+    // `x` and `y` are physical pixels in landscape orientation (296x128)
+    fn set_physical_pixel(&mut self, x: usize, y: usize, on: bool) {
+        if x >= PANEL_HEIGHT || y >= PANEL_WIDTH {
+            return;
+        }
+        // rotated 90° clockwise: the landscape x axis is the RAM y axis
+        let (ram_x, ram_y) = (PANEL_WIDTH - 1 - y, x);
+        let index = ram_y * ROW_BYTES + ram_x / 8;
+        let mask = 0x80 >> (ram_x % 8);
+        if on {
+            self.buffer[index] &= !mask; // black
+        } else {
+            self.buffer[index] |= mask;
+        }
+    }
+
+    pub fn physical_pixel(&self, x: usize, y: usize) -> bool {
+        if x >= PANEL_HEIGHT || y >= PANEL_WIDTH {
+            return false;
+        }
+        let (ram_x, ram_y) = (PANEL_WIDTH - 1 - y, x);
+        self.buffer[ram_y * ROW_BYTES + ram_x / 8] & (0x80 >> (ram_x % 8)) == 0
+    }
+}
+
+impl Canvas for EpaperFramebuffer {
+    fn width(&self) -> u16 {
+        PANEL_HEIGHT as u16 / SCALE
+    }
+
+    fn height(&self) -> u16 {
+        PANEL_WIDTH as u16 / SCALE
+    }
+
+    fn set_pixel(&mut self, x: u16, y: u16, on: bool) {
+        for dx in 0..SCALE {
+            for dy in 0..SCALE {
+                self.set_physical_pixel((x * SCALE + dx) as usize, (y * SCALE + dy) as usize, on);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.buffer = [0xFF; BUFFER_SIZE];
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refresh {
+    Full,
+    Partial,
+}
+
+// decides how (and if) the panel should be refreshed after a render
+#[derive(Debug)]
+pub struct RefreshPolicy {
+    full_refresh_every: u8, // number of partial refreshes between two full refreshes
+    partial_refreshes: u8, // partial refreshes since the last full refresh
+}
+
+impl RefreshPolicy {
+    pub fn new(full_refresh_every: u8) -> Self {
+        // the content of the panel is unknown at boot, start with a full refresh
+        RefreshPolicy { full_refresh_every, partial_refreshes: full_refresh_every }
+    }
+
+    // None if the rendered content didn't change, nothing to do
+    pub fn next(&mut self, changed: bool) -> Option<Refresh> {
+        if !changed {
+            return None;
+        }
+        if self.partial_refreshes >= self.full_refresh_every {
+            self.partial_refreshes = 0;
+            Some(Refresh::Full)
+        } else {
+            self.partial_refreshes += 1;
+            Some(Refresh::Partial)
+        }
+    }
+
+    // e.g. after a reset of the controller
+    pub fn force_full(&mut self) {
+        self.partial_refreshes = self.full_refresh_every;
+    }
+}
+
+#[cfg(target_arch = "riscv32")]
+pub use driver::{Epaper, EpaperRenderer, Ram};
+
+#[cfg(target_arch = "riscv32")]
+mod driver {
+    use embassy_time::{Duration, Timer};
+    use esp_hal::{
+        Blocking,
+        gpio::{Input, Output},
+        spi::master::Spi,
+    };
+
+    use super::{EpaperFramebuffer, Refresh, RefreshPolicy};
+    use crate::canvas::render_state;
+    use crate::display::{TramDisplay, UiState};
+
+    mod epaper_commands {
+        pub const DRIVER_OUTPUT_CONTROL: u8 = 0x01;
+        pub const DEEP_SLEEP: u8 = 0x10;
+        pub const DATA_ENTRY_MODE: u8 = 0x11;
+        pub const SW_RESET: u8 = 0x12;
+        pub const TEMPERATURE_SENSOR: u8 = 0x18;
+        pub const MASTER_ACTIVATION: u8 = 0x20;
+        pub const DISPLAY_UPDATE_CONTROL_1: u8 = 0x21;
+        pub const DISPLAY_UPDATE_CONTROL_2: u8 = 0x22;
+        pub const WRITE_RAM_BW: u8 = 0x24; // new image
+        pub const WRITE_RAM_RED: u8 = 0x26; // used as the previous image by the partial refresh
+        pub const BORDER_WAVEFORM: u8 = 0x3C;
+        pub const SET_RAM_X_RANGE: u8 = 0x44;
+        pub const SET_RAM_Y_RANGE: u8 = 0x45;
+        pub const SET_RAM_X_COUNTER: u8 = 0x4E;
+        pub const SET_RAM_Y_COUNTER: u8 = 0x4F;
+
+        // DISPLAY_UPDATE_CONTROL_2 sequences
+        pub const UPDATE_FULL: u8 = 0xF7; // load temperature and LUT, display mode 1
+        pub const UPDATE_PARTIAL: u8 = 0xFF; // load temperature and LUT, display mode 2
+    }
+
+    pub struct Epaper<'a> {
+        spi: Spi<'a, Blocking>,
+        dc: Output<'a>, // low: command, high: data
+        rst: Output<'a>,
+        busy: Input<'a>, // high while the controller is working
+    }
+
+    // Source: SSD1680 datasheet and the Waveshare 2.9" V2 example code
+    impl<'a> Epaper<'a> {
+        pub fn new(spi: Spi<'a, Blocking>, dc: Output<'a>, rst: Output<'a>, busy: Input<'a>) -> Self {
+            Self { spi, dc, rst, busy }
+        }
+
+        // hardware reset and configuration, needs to be called before any other command (and to wake up from deep sleep)
+        pub async fn init(&mut self) {
+            self.rst.set_low();
+            Timer::after(Duration::from_millis(10)).await;
+            self.rst.set_high();
+            Timer::after(Duration::from_millis(10)).await;
+            self.wait_busy().await;
+
+            self.command(epaper_commands::SW_RESET, &[]).await;
+            self.wait_busy().await;
+
+            let last_row = (super::PANEL_HEIGHT - 1) as u16;
+            self.command(epaper_commands::DRIVER_OUTPUT_CONTROL, &[last_row as u8, (last_row >> 8) as u8, 0x00]).await;
+            self.command(epaper_commands::DATA_ENTRY_MODE, &[0x03]).await; // x and y increment
+            self.command(epaper_commands::SET_RAM_X_RANGE, &[0x00, (super::ROW_BYTES - 1) as u8]).await;
+            self.command(epaper_commands::SET_RAM_Y_RANGE, &[0x00, 0x00, last_row as u8, (last_row >> 8) as u8]).await;
+            self.command(epaper_commands::BORDER_WAVEFORM, &[0x05]).await;
+            self.command(epaper_commands::DISPLAY_UPDATE_CONTROL_1, &[0x00, 0x80]).await;
+            self.command(epaper_commands::TEMPERATURE_SENSOR, &[0x80]).await; // internal sensor
+            self.wait_busy().await;
+        }
+
+        pub async fn write_ram(&mut self, ram: Ram, framebuffer: &EpaperFramebuffer) {
+            self.command(epaper_commands::SET_RAM_X_COUNTER, &[0x00]).await;
+            self.command(epaper_commands::SET_RAM_Y_COUNTER, &[0x00, 0x00]).await;
+            let command = match ram {
+                Ram::Current => epaper_commands::WRITE_RAM_BW,
+                Ram::Previous => epaper_commands::WRITE_RAM_RED,
+            };
+            self.command(command, framebuffer.as_bytes()).await;
+        }
+
+        // show the content of the RAM on the panel, returns once the refresh is done
+        pub async fn refresh(&mut self, refresh: Refresh) {
+            let sequence = match refresh {
+                Refresh::Full => epaper_commands::UPDATE_FULL,
+                Refresh::Partial => epaper_commands::UPDATE_PARTIAL,
+            };
+            self.command(epaper_commands::DISPLAY_UPDATE_CONTROL_2, &[sequence]).await;
+            self.command(epaper_commands::MASTER_ACTIVATION, &[]).await;
+            self.wait_busy().await;
+        }
+
+        // the panel keeps its image without power, `init` must be called to wake it up
+        pub async fn deep_sleep(&mut self) {
+            self.command(epaper_commands::DEEP_SLEEP, &[0x01]).await;
+        }
+
+        async fn command(&mut self, command: u8, data: &[u8]) {
+            self.dc.set_low();
+            if self.spi.write(&[command]).is_err() {
+                esp_println::println!("Error when sending e-paper command 0x{:02X}", command);
+                return;
+            }
+            if data.is_empty() {
+                return;
+            }
+            self.dc.set_high();
+            if self.spi.write(data).is_err() {
+                esp_println::println!("Error when sending e-paper data for 0x{:02X}", command);
+            }
+        }
+
+        async fn wait_busy(&mut self) {
+            self.busy.wait_for_low().await;
+        }
+    }
+
+    pub enum Ram {
+        Current,
+        Previous,
+    }
+
+    pub struct EpaperRenderer<'a> {
+        epaper: Epaper<'a>,
+        // the framebuffers are 4.7kB each, they are kept outside of the renderer (e.g. in a static)
+        // so that they don't end up in the task arena
+        framebuffer: &'a mut EpaperFramebuffer, // what we want on the screen
+        flushed: &'a mut EpaperFramebuffer, // what is currently on the screen
+        policy: RefreshPolicy,
+    }
+
+    impl<'a> EpaperRenderer<'a> {
+        pub fn new(
+            epaper: Epaper<'a>,
+            framebuffer: &'a mut EpaperFramebuffer,
+            flushed: &'a mut EpaperFramebuffer,
+            full_refresh_every: u8,
+        ) -> Self {
+            EpaperRenderer { epaper, framebuffer, flushed, policy: RefreshPolicy::new(full_refresh_every) }
+        }
+    }
+
+    // e-paper has no backlight, `backlight_on` is ignored
+    impl TramDisplay for EpaperRenderer<'_> {
+        async fn render<'b, const LINES: usize, const DIRECTIONS: usize, const PASSAGES: usize>(
+            &'b mut self,
+            state: &'b UiState<LINES, DIRECTIONS, PASSAGES>,
+        ) {
+            render_state(self.framebuffer, state);
+            let changed = self.framebuffer != self.flushed;
+            let Some(refresh) = self.policy.next(changed) else { return };
+
+            self.epaper.init().await; // wake up from deep sleep, the RAM content is not guaranteed after the reset
+            // the partial refresh only drives the pixels that differ between the previous and the current image
+            let previous = match refresh {
+                Refresh::Full => &*self.framebuffer,
+                Refresh::Partial => &*self.flushed,
+            };
+            self.epaper.write_ram(Ram::Previous, previous).await;
+            self.epaper.write_ram(Ram::Current, self.framebuffer).await;
+            self.epaper.refresh(refresh).await;
+            self.epaper.deep_sleep().await;
+
+            self.flushed.copy_from(self.framebuffer);
+        }
+
+        // nothing to check over SPI, a missing panel just keeps BUSY low
+        async fn healthcheck(&mut self) {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_with_a_full_refresh() {
+        let mut policy = RefreshPolicy::new(3);
+        assert_eq!(policy.next(true), Some(Refresh::Full));
+    }
+
+    #[test]
+    fn only_refreshes_when_content_changed() {
+        let mut policy = RefreshPolicy::new(3);
+        assert_eq!(policy.next(true), Some(Refresh::Full));
+        assert_eq!(policy.next(false), None);
+        assert_eq!(policy.next(false), None);
+        assert_eq!(policy.next(true), Some(Refresh::Partial));
+    }
+
+    #[test]
+    fn full_refresh_after_some_partial_ones() {
+        let mut policy = RefreshPolicy::new(2);
+        assert_eq!(policy.next(true), Some(Refresh::Full));
+        assert_eq!(policy.next(true), Some(Refresh::Partial));
+        assert_eq!(policy.next(true), Some(Refresh::Partial));
+        assert_eq!(policy.next(true), Some(Refresh::Full));
+        assert_eq!(policy.next(true), Some(Refresh::Partial));
+
+        policy.force_full();
+        assert_eq!(policy.next(true), Some(Refresh::Full));
+    }
+
+    #[test]
+    fn canvas_pixels_are_scaled_and_rotated() {
+        let mut framebuffer = EpaperFramebuffer::new();
+        assert_eq!((framebuffer.width(), framebuffer.height()), (148, 64));
+
+        framebuffer.set_pixel(1, 0, true);
+        for (x, y) in [(2, 0), (3, 0), (2, 1), (3, 1)] {
+            assert!(framebuffer.physical_pixel(x, y));
+        }
+        assert!(!framebuffer.physical_pixel(0, 0));
+        assert!(!framebuffer.physical_pixel(4, 0));
+        // top left of the landscape canvas is the end of the first RAM row, 0 bits are black
+        assert_eq!(framebuffer.as_bytes()[2 * ROW_BYTES + ROW_BYTES - 1], 0xFC);
+
+        framebuffer.clear();
+        assert!(framebuffer.as_bytes().iter().all(|b| *b == 0xFF));
+    }
+
+    #[test]
+    fn rendering_the_same_state_twice_gives_the_same_framebuffer() {
+        use crate::canvas::render_state;
+        use crate::display::{Severity, Staleness, StatusMessage, UiState};
+        use embassy_time::Instant;
+
+        let mut state: UiState = UiState::new(Staleness::default(), Instant::from_secs(0));
+        state.current_message = Some(StatusMessage {
+            text: heapless::String::try_from("Wifi connected !").unwrap(),
+            severity: Severity::Info,
+            expires_at: None,
+        });
+        let mut first = EpaperFramebuffer::new();
+        let mut second = EpaperFramebuffer::new();
+        render_state(&mut first, &state);
+        render_state(&mut second, &state);
+        assert!(first == second);
+        assert!(first != EpaperFramebuffer::new());
+    }
+}
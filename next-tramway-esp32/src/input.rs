@@ -0,0 +1,266 @@
+// Input devices, decoded without touching the GPIOs so it can be tested on the host:
+// - button gestures, recognized from the edges of the button input
+//   the state machine only works on timestamps, the task reading the GPIO feeds it the edges and calls `timeout`
+//   when `deadline` is reached, so it can be tested with made up timings
+// - rotary encoder (KY-040), decoded from the levels of its two quadrature outputs
+
+use embassy_time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gesture {
+    ShortPress, // reported once the double click window is over
+    LongPress, // reported while the button is still held, as soon as it's long enough
+    DoubleClick, // reported on the second press
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GestureTimings {
+    pub debounce: Duration, // edges closer than this are contact bounces
+    pub long_press: Duration, // minimum hold time of a long press
+    pub double_click: Duration, // maximum time between the release of the first click and the second press
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    Pressed { since: Instant }, // first press, becomes a long press if held long enough
+    Released { pressed_at: Instant, at: Instant }, // first click done, waiting for a second one
+    Held, // the gesture is already reported, waiting for the release
+}
+
+pub struct GestureRecognizer {
+    timings: GestureTimings,
+    state: State,
+}
+
+impl GestureRecognizer {
+    pub fn new(timings: GestureTimings) -> Self {
+        GestureRecognizer { timings, state: State::Idle }
+    }
+
+    pub fn pressed(&mut self, now: Instant) -> Option<Gesture> {
+        match self.state {
+            State::Idle => {
+                self.state = State::Pressed { since: now };
+                None
+            }
+            State::Released { pressed_at, at } if now - at < self.timings.debounce => {
+                // bounce of the release, the first press goes on
+                self.state = State::Pressed { since: pressed_at };
+                None
+            }
+            State::Released { .. } => {
+                self.state = State::Held;
+                Some(Gesture::DoubleClick)
+            }
+            // same edge twice, can happen when an edge is missed
+            State::Pressed { .. } | State::Held => None,
+        }
+    }
+
+    pub fn released(&mut self, now: Instant) -> Option<Gesture> {
+        match self.state {
+            State::Pressed { since } if now - since < self.timings.debounce => {
+                // too short to be a real press
+                self.state = State::Idle;
+            }
+            State::Pressed { since } => {
+                self.state = State::Released { pressed_at: since, at: now };
+            }
+            State::Held => self.state = State::Idle,
+            State::Idle | State::Released { .. } => {}
+        }
+        None
+    }
+
+    // when `timeout` has to be called if no edge comes before, None if the recognizer is waiting for an edge
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.state {
+            State::Pressed { since } => Some(since + self.timings.long_press),
+            State::Released { at, .. } => Some(at + self.timings.double_click),
+            State::Idle | State::Held => None,
+        }
+    }
+
+    pub fn timeout(&mut self, now: Instant) -> Option<Gesture> {
+        match self.state {
+            State::Pressed { since } if now >= since + self.timings.long_press => {
+                self.state = State::Held;
+                Some(Gesture::LongPress)
+            }
+            State::Released { at, .. } if now >= at + self.timings.double_click => {
+                self.state = State::Idle;
+                Some(Gesture::ShortPress)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Turn {
+    Clockwise,
+    CounterClockwise,
+}
+
+// +1 / -1 for each valid transition of the (A, B) levels, indexed by `previous << 2 | current`
+// transitions where both levels change are missed edges and are ignored
+// A (CLK on the KY-040) changing first means clockwise
+const QUADRATURE_TRANSITIONS: [i8; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+
+pub struct QuadratureDecoder {
+    levels: u8, // A << 1 | B
+    count: i8, // transitions since the last reported turn
+    transitions_per_detent: i8,
+}
+
+impl QuadratureDecoder {
+    // `a` and `b` are the levels of the pins at startup
+    // the KY-040 does a whole quadrature cycle (4 transitions) between two detents, some encoders only do 2
+    pub fn new(a: bool, b: bool, transitions_per_detent: u8) -> Self {
+        QuadratureDecoder { levels: Self::levels(a, b), count: 0, transitions_per_detent: transitions_per_detent as i8 }
+    }
+
+    fn levels(a: bool, b: bool) -> u8 {
+        (a as u8) << 1 | b as u8
+    }
+
+    // to call on every edge of A or B, returns a turn once the encoder reached the next detent
+    // contact bounces go back and forth between two levels, so they cancel out
+    pub fn update(&mut self, a: bool, b: bool) -> Option<Turn> {
+        let levels = Self::levels(a, b);
+        self.count += QUADRATURE_TRANSITIONS[(self.levels << 2 | levels) as usize];
+        self.levels = levels;
+
+        if self.count >= self.transitions_per_detent {
+            self.count = 0;
+            Some(Turn::Clockwise)
+        } else if self.count <= -self.transitions_per_detent {
+            self.count = 0;
+            Some(Turn::CounterClockwise)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMINGS: GestureTimings = GestureTimings {
+        debounce: Duration::from_millis(30),
+        long_press: Duration::from_millis(800),
+        double_click: Duration::from_millis(300),
+    };
+
+    enum Edge {
+        Press(u64),
+        Release(u64),
+    }
+
+    // feed the edges (timestamps in ms), calling `timeout` like the button task does, and collect the gestures
+    fn recognize(edges: &[Edge]) -> std::vec::Vec<(u64, Gesture)> {
+        let mut recognizer = GestureRecognizer::new(TIMINGS);
+        let mut gestures = std::vec::Vec::new();
+        for edge in edges.iter().chain([Edge::Release(10_000)].iter()) {
+            let at = match edge {
+                Edge::Press(at) | Edge::Release(at) => Instant::from_millis(*at),
+            };
+            while let Some(deadline) = recognizer.deadline().filter(|deadline| *deadline <= at) {
+                if let Some(gesture) = recognizer.timeout(deadline) {
+                    gestures.push((deadline.as_millis(), gesture));
+                }
+            }
+            let gesture = match edge {
+                Edge::Press(_) => recognizer.pressed(at),
+                Edge::Release(_) => recognizer.released(at),
+            };
+            if let Some(gesture) = gesture {
+                gestures.push((at.as_millis(), gesture));
+            }
+        }
+        gestures
+    }
+
+    use Edge::{Press, Release};
+
+    #[test]
+    fn short_press_after_the_double_click_window() {
+        assert_eq!(recognize(&[Press(0), Release(100)]), [(400, Gesture::ShortPress)]);
+    }
+
+    #[test]
+    fn long_press_while_held() {
+        assert_eq!(recognize(&[Press(0), Release(2000)]), [(800, Gesture::LongPress)]);
+        assert_eq!(recognize(&[Press(0), Release(799)]), [(1099, Gesture::ShortPress)]);
+    }
+
+    #[test]
+    fn double_click() {
+        assert_eq!(recognize(&[Press(0), Release(100), Press(250), Release(350)]), [(250, Gesture::DoubleClick)]);
+        // too slow, two short presses
+        assert_eq!(
+            recognize(&[Press(0), Release(100), Press(450), Release(550)]),
+            [(400, Gesture::ShortPress), (850, Gesture::ShortPress)]
+        );
+        // holding the second press doesn't make it a long press
+        assert_eq!(recognize(&[Press(0), Release(100), Press(250), Release(2000)]), [(250, Gesture::DoubleClick)]);
+    }
+
+    #[test]
+    fn ignores_bounces() {
+        // glitch shorter than the debounce time
+        assert!(recognize(&[Press(0), Release(10)]).is_empty());
+        // bouncing contact on the press and on the release
+        assert_eq!(
+            recognize(&[Press(0), Release(5), Press(8), Release(120), Press(125), Release(130)]),
+            [(430, Gesture::ShortPress)]
+        );
+        // bounce during a long press
+        assert_eq!(recognize(&[Press(0), Release(500), Press(510), Release(1500)]), [(800, Gesture::LongPress)]);
+    }
+
+    #[test]
+    fn repeated_edges_are_harmless() {
+        assert_eq!(recognize(&[Press(0), Press(50), Release(100), Release(110)]), [(400, Gesture::ShortPress)]);
+    }
+
+    // (A, B) levels of one detent clockwise, starting from the rest position of the KY-040 (both high)
+    const CLOCKWISE: [(bool, bool); 4] = [(false, true), (false, false), (true, false), (true, true)];
+
+    fn turns(decoder: &mut QuadratureDecoder, levels: &[(bool, bool)]) -> std::vec::Vec<Turn> {
+        levels.iter().filter_map(|(a, b)| decoder.update(*a, *b)).collect()
+    }
+
+    #[test]
+    fn decodes_both_directions() {
+        let mut decoder = QuadratureDecoder::new(true, true, 4);
+        assert_eq!(turns(&mut decoder, &CLOCKWISE), [Turn::Clockwise]);
+        assert_eq!(turns(&mut decoder, &[CLOCKWISE, CLOCKWISE].concat()), [Turn::Clockwise, Turn::Clockwise]);
+
+        let mut counter_clockwise: std::vec::Vec<_> = CLOCKWISE.iter().rev().skip(1).copied().collect();
+        counter_clockwise.push((true, true));
+        assert_eq!(turns(&mut decoder, &counter_clockwise), [Turn::CounterClockwise]);
+    }
+
+    #[test]
+    fn only_reports_whole_detents() {
+        let mut decoder = QuadratureDecoder::new(true, true, 4);
+        // half way then back to the detent
+        assert!(turns(&mut decoder, &[(false, true), (false, false), (false, true), (true, true)]).is_empty());
+        assert_eq!(turns(&mut decoder, &CLOCKWISE), [Turn::Clockwise]);
+
+        let mut decoder = QuadratureDecoder::new(true, true, 2);
+        assert_eq!(turns(&mut decoder, &CLOCKWISE), [Turn::Clockwise, Turn::Clockwise]);
+    }
+
+    #[test]
+    fn bounces_cancel_out() {
+        let mut decoder = QuadratureDecoder::new(true, true, 4);
+        let bouncing = [(false, true), (true, true), (false, true), (false, false), (false, true), (false, false), (true, false), (true, true)];
+        assert_eq!(turns(&mut decoder, &bouncing), [Turn::Clockwise]);
+        // both levels changing at once: an edge was missed, nothing can be said about the direction
+        assert!(turns(&mut decoder, &[(false, false), (true, true)]).is_empty());
+    }
+}
@@ -0,0 +1,260 @@
+// JSON payload of the line topics, an alternative to the line oriented text format of the protocol module:
+// {
+//   "line": "Tram C",
+//   "direction": 1,
+//   "passages": [{"destination": "Plaine des sports", "minutes": 3, "realtime": true, "at": 1760625480}],
+//   "update_at": "14:35:10",
+//   "disruption": "Arret Gares non desservi",
+//   "staleness": {"stale_after": 1800, "no_data_after": 3600}
+// }
+// `direction`, `disruption`, `staleness` (in seconds, for a line published less often than the others) and `at` (unix
+// time of the arrival) are optional, the unknown fields are ignored
+// Parsed with serde-json-core, without allocation: the strings go straight into the fixed capacity buffers of the
+// UI state, the ones that don't fit are truncated and reported like in the text format
+
+use core::fmt;
+use core::marker::PhantomData;
+
+use embassy_time::Duration;
+use heapless::{String, Vec};
+use serde::Deserialize;
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+
+use crate::display::{ArrivalKind, Destination, Disruption, LineId, LineName, Staleness, TramNextPassage, UiCommand, UpdateAt};
+use crate::protocol::{self, Field, ParseError, Parsed};
+
+// the strings with escapes (`\"`, `\u00e8`...) are decoded in a buffer of this size on the stack,
+// a longer one is rejected as invalid JSON instead of being reported as an overflow of its field
+const UNESCAPE_BUFFER_SIZE: usize = 128;
+
+pub fn looks_like_json(payload: &str) -> bool {
+    payload.trim_start().starts_with('{')
+}
+
+// `line_id` and `direction_id` come from the topic, the `direction` of the payload must match it if present
+pub fn parse_direction<const PASSAGES: usize>(line_id: LineId, direction_id: usize, payload: &str) -> Result<Parsed<PASSAGES>, ParseError> {
+    let mut unescape_buffer = [0; UNESCAPE_BUFFER_SIZE];
+    let (payload, _): (Payload<PASSAGES>, _) =
+        serde_json_core::from_str_escaped(payload, &mut unescape_buffer).map_err(|_| ParseError::InvalidJson)?;
+
+    if payload.direction.is_some_and(|direction| direction != direction_id) {
+        return Err(ParseError::InvalidDirectionId);
+    }
+    let mut overflow = None;
+    let line = payload.line.fit(Field::LineName, &mut overflow);
+    let update_at = payload.update_at.fit(Field::UpdateAt, &mut overflow);
+    let mut next_passages = Vec::new();
+    for passage in payload.passages.passages {
+        let _ = next_passages.push(TramNextPassage {
+            destination: passage.destination.fit(Field::Destination, &mut overflow),
+            relative_arrival: passage.minutes,
+            arrival_at: passage.at,
+            arrival_kind: if passage.realtime { ArrivalKind::Realtime } else { ArrivalKind::Scheduled },
+        });
+    }
+    if payload.passages.overflow {
+        overflow.get_or_insert(Field::Passages);
+    }
+    let disruption = payload.disruption.map(|disruption| disruption.fit(Field::Disruption, &mut overflow));
+    let staleness = payload.staleness.map(|staleness| Staleness {
+        stale_after: Duration::from_secs(staleness.stale_after.into()),
+        no_data_after: Duration::from_secs(staleness.no_data_after.into()),
+    });
+
+    Ok((UiCommand::UpdateDirection { line_id, line, direction_id, next_passages, update_at, disruption, staleness }, overflow))
+}
+
+#[derive(Deserialize)]
+struct Payload<const PASSAGES: usize> {
+    line: Capped<LineName>,
+    direction: Option<usize>,
+    passages: Passages<PASSAGES>,
+    update_at: Capped<UpdateAt>,
+    disruption: Option<Capped<Disruption>>,
+    staleness: Option<StalenessSecs>,
+}
+
+#[derive(Deserialize)]
+struct StalenessSecs {
+    stale_after: u32,
+    no_data_after: u32,
+}
+
+#[derive(Deserialize)]
+struct Passage {
+    destination: Capped<Destination>,
+    minutes: u8,
+    realtime: bool, // false for the timetable times, like `S` in the text format
+    at: Option<u64>,
+}
+
+// string deserialized into a fixed capacity buffer, cut at a char boundary if it doesn't fit
+// the parser reports which field was cut, see `fit`
+struct Capped<T> {
+    value: T,
+    truncated: bool,
+}
+
+impl<const N: usize> Capped<String<N>> {
+    // the first field truncated is kept in `overflow`
+    fn fit(self, field: Field, overflow: &mut Option<Field>) -> String<N> {
+        if self.truncated {
+            overflow.get_or_insert(field);
+        }
+        self.value
+    }
+}
+
+impl<'de, const N: usize> Deserialize<'de> for Capped<String<N>> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct CappedVisitor<T>(PhantomData<T>);
+
+        impl<const N: usize> Visitor<'_> for CappedVisitor<String<N>> {
+            type Value = Capped<String<N>>;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a string")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                let (value, truncated) = protocol::truncate(value);
+                Ok(Capped { value, truncated })
+            }
+        }
+
+        deserializer.deserialize_str(CappedVisitor(PhantomData))
+    }
+}
+
+// the passages past the capacity are still parsed (so a syntax error is reported first), then dropped
+struct Passages<const PASSAGES: usize> {
+    passages: Vec<Passage, PASSAGES>,
+    overflow: bool,
+}
+
+impl<'de, const PASSAGES: usize> Deserialize<'de> for Passages<PASSAGES> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PassagesVisitor<const PASSAGES: usize>;
+
+        impl<'de, const PASSAGES: usize> Visitor<'de> for PassagesVisitor<PASSAGES> {
+            type Value = Passages<PASSAGES>;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a list of passages")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut passages = Passages { passages: Vec::new(), overflow: false };
+                while let Some(passage) = seq.next_element()? {
+                    if passages.passages.push(passage).is_err() {
+                        passages.overflow = true;
+                    }
+                }
+                Ok(passages)
+            }
+        }
+
+        deserializer.deserialize_seq(PassagesVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // for the payloads that fit, `reports_what_doesnt_fit` checks the others
+    fn parse(payload: &str) -> Result<UiCommand, ParseError> {
+        parse_direction(LineId::try_from("C").unwrap(), 1, payload).map(|(command, overflow)| {
+            assert_eq!(overflow, None, "{payload}");
+            command
+        })
+    }
+
+    #[test]
+    fn parses_the_passages_and_the_disruption() {
+        let payload = r#"{
+            "line": "Tram C",
+            "direction": 1,
+            "passages": [
+                {"destination": "Plaine des sports", "minutes": 3, "realtime": true, "at": 1760625480},
+                {"destination": "Universit\u00e9 | Condillac", "minutes": 8, "realtime": false}
+            ],
+            "update_at": "14:35:10",
+            "disruption": "Arret \"Gares\" non desservi",
+            "staleness": {"stale_after": 1800, "no_data_after": 3600}
+        }"#;
+        let Ok(UiCommand::UpdateDirection { line_id, line, direction_id, next_passages, update_at, disruption, staleness }) = parse(payload) else {
+            panic!("unexpected result: {:?}", parse(payload));
+        };
+        assert_eq!((line_id.as_str(), line.as_str(), direction_id), ("C", "Tram C", 1));
+        assert_eq!(update_at, "14:35:10");
+        assert_eq!(next_passages.len(), 2);
+        assert_eq!(next_passages[0].destination, "Plaine des sports");
+        assert_eq!(next_passages[0].relative_arrival, 3);
+        assert_eq!(next_passages[0].arrival_kind, ArrivalKind::Realtime);
+        assert_eq!(next_passages[0].arrival_at, Some(1_760_625_480));
+        assert_eq!(next_passages[1].arrival_at, None);
+        // unlike the text format, the destinations can contain a `|`, and the escapes of `json.dumps` are decoded
+        assert_eq!(next_passages[1].destination, "Université | Condillac");
+        assert_eq!(next_passages[1].arrival_kind, ArrivalKind::Scheduled);
+        assert_eq!(disruption.as_deref(), Some("Arret \"Gares\" non desservi"));
+        assert_eq!(staleness, Some(Staleness { stale_after: Duration::from_secs(1800), no_data_after: Duration::from_secs(3600) }));
+    }
+
+    #[test]
+    fn optional_fields() {
+        let Ok(UiCommand::UpdateDirection { next_passages, disruption, staleness, .. }) =
+            parse(r#"{"line": "Tram C", "passages": [], "update_at": "14:35:10"}"#)
+        else {
+            panic!("the direction, the disruption and the staleness are optional");
+        };
+        assert!(next_passages.is_empty());
+        assert!(disruption.is_none());
+        assert!(staleness.is_none());
+    }
+
+    #[test]
+    fn reports_what_doesnt_fit() {
+        let truncated = |payload: &str| match parse_direction::<3>(LineId::try_from("C").unwrap(), 1, payload) {
+            Ok((UiCommand::UpdateDirection { line, next_passages, disruption, .. }, overflow)) => (line, next_passages, disruption, overflow),
+            other => panic!("unexpected result: {:?}", other),
+        };
+
+        // the first passages are kept
+        let passage = |minutes: u8| std::format!(r#"{{"destination": "Gare", "minutes": {minutes}, "realtime": true}}"#);
+        let payload = std::format!(
+            r#"{{"line": "Tram C", "passages": [{}, {}, {}, {}], "update_at": "14:35:10"}}"#,
+            passage(1), passage(5), passage(9), passage(13)
+        );
+        let (_, passages, _, overflow) = truncated(&payload);
+        let minutes: std::vec::Vec<_> = passages.iter().map(|p| p.relative_arrival).collect();
+        assert_eq!((minutes.as_slice(), overflow), ([1, 5, 9].as_slice(), Some(Field::Passages)));
+        assert!(matches!(parse_direction::<4>(LineId::try_from("C").unwrap(), 1, &payload), Ok((_, None))));
+
+        let (line, _, _, overflow) = truncated(r#"{"line": "Tram C (Gieres - Plaine des sports)", "passages": [], "update_at": "14:35:10"}"#);
+        assert_eq!((line.as_str(), overflow), ("Tram C (Gieres -", Some(Field::LineName)));
+        // decoded from an escape, and cut before the accented letter rather than in the middle of it
+        let long_destination = r#"{"line": "Tram C", "passages": [{"destination": "Universite - Condillac - Saint-\u00e9", "minutes": 3, "realtime": true}], "update_at": "14:35:10"}"#;
+        let (_, passages, _, overflow) = truncated(long_destination);
+        assert_eq!((passages[0].destination.as_str(), overflow), ("Universite - Condillac - Saint-", Some(Field::Destination)));
+
+        let disruption = "Arret Gares non desservi jusqu'a nouvel ordre, reportez-vous a l'arret Alsace-Lorraine";
+        let (_, _, disruption, overflow) =
+            truncated(&std::format!(r#"{{"line": "Tram C", "passages": [], "update_at": "14:35:10", "disruption": "{disruption}"}}"#));
+        assert_eq!((disruption.unwrap().len(), overflow), (64, Some(Field::Disruption)));
+    }
+
+    #[test]
+    fn rejects_invalid_payloads() {
+        assert_eq!(parse(r#"{"line": "Tram C", "passages": []"#).unwrap_err(), ParseError::InvalidJson);
+        assert_eq!(parse(r#"{"line": "Tram C", "passages": []}"#).unwrap_err(), ParseError::InvalidJson);
+        assert_eq!(
+            parse(r#"{"line": "Tram C", "passages": [{"destination": "Gare", "minutes": -1, "realtime": true}], "update_at": "14:35:10"}"#).unwrap_err(),
+            ParseError::InvalidJson
+        );
+        assert_eq!(
+            parse(r#"{"line": "Tram C", "direction": 2, "passages": [], "update_at": "14:35:10"}"#).unwrap_err(),
+            ParseError::InvalidDirectionId
+        );
+    }
+}
@@ -1,17 +1,18 @@
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
-use embassy_time::{Timer, Duration};
-use esp_hal::{Blocking, i2c::master::I2c};
+use core::fmt::Write;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal_async::i2c::I2c;
 use heapless::String;
 
-use crate::display::{TramDirectionState, TramDisplay};
-use core::fmt::Write;
+use crate::clock::LocalTime;
+use crate::display::{ArrivalKind, Freshness, MessagePlacement, TramDirectionState, TramDisplay, UiState, WallClock};
+use crate::font;
 
 // add space padding at the end of the string to ensure that when we update the LCD, we properly clear the previous content if the new one is shorter
 fn pad_to_width<const N: usize>(
     s: &mut heapless::String<N>,
     width: usize,
 ) {
-    let len = s.len();
+    let len = s.chars().count();
 
     if len < width {
         for _ in 0..(width - len) {
@@ -46,109 +47,461 @@ pub fn wrap_text<const OUT: usize>(
     }
 }
 
-pub struct LcdRenderer<'a> {
-    lcd_screen: Lcd<'a>, // handle to the LCD screen, used to send commands and data to the LCD
-    last_rendered: Option<TramDirectionState>, // we keep track of the last rendered state to avoid unnecessary updates to the LCD, which can be slow (especially over I2C)
-    last_rendered_line: Option<heapless::String<16>>, 
-    display_buffer: [heapless::String<20>; 4], // we keep a buffer of the currently displayed content on the LCD to minimize the number of updates, which is slow 
+// could be more generic, but this is good enough for our use case, and we can always refactor later if needed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LcdGeometry {
+    L1602, // 16 characters, 2 lines
+    L2004, // 20 characters, 4 lines
 }
 
-impl<'a> LcdRenderer<'a> {
-    pub fn new(lcd_screen: Lcd<'a>) -> Self {
-        LcdRenderer { 
-            lcd_screen,
-            last_rendered: None,
-            last_rendered_line: None,
-            display_buffer: [
-                heapless::String::new(),
-                heapless::String::new(),
-                heapless::String::new(),
-                heapless::String::new(),
-             ]
+impl LcdGeometry {
+    pub fn rows(&self) -> usize {
+        match self {
+            LcdGeometry::L1602 => 2,
+            LcdGeometry::L2004 => 4,
         }
     }
 
+    pub fn columns(&self) -> usize {
+        match self {
+            LcdGeometry::L1602 => 16,
+            LcdGeometry::L2004 => 20,
+        }
+    }
+}
 
-    async fn render_line(&mut self, line: &heapless::String<16>,tram_direction_state: &TramDirectionState) {
-        if self.last_rendered.as_ref() == Some(tram_direction_state) 
-          && self.last_rendered_line.as_ref() == Some(line) {
-            // technically the display buffer would also be the same
-            // but it skips the whole rendering logic at the expense of some memory 
+// text of each row of the screen, padded to the width of the screen (rows past the height of the screen are left empty)
+// this is the layout logic of the LCD, kept apart from the driver so it can be used by the simulator and tested on the host
+pub type LcdRow = heapless::String<60>; // 20 characters, up to 3 bytes each in UTF-8
+pub type LcdScreen = [LcdRow; 4];
+
+// what should be on the screen for this state, None if there is nothing to show (the screen is left as is)
+// the screen is blanked once the last message expires or is cleared, so it doesn't stay forever
+// (once the device has a clock, the clock is shown instead)
+pub fn render_screen<const LINES: usize, const DIRECTIONS: usize, const PASSAGES: usize>(
+    state: &UiState<LINES, DIRECTIONS, PASSAGES>,
+    geometry: LcdGeometry,
+) -> Option<LcdScreen> {
+    let message = state.visible_message();
+    let mut screen = match message {
+        Some((message, MessagePlacement::FullScreen)) => render_message(&message.text, geometry),
+        _ => match state.clock_screen() {
+            Some(local) => render_clock(&local, geometry),
+            None if state.lines.is_empty() => LcdScreen::default(),
+            None => {
+                let (line, direction) = state.current_screen()?;
+                match geometry {
+                    LcdGeometry::L2004 => render_direction(&line.line, direction, state.now, state.clock.as_ref()),
+                    LcdGeometry::L1602 => render_direction_small(&line.line, direction, state.now, state.clock.as_ref()),
+                }
+            }
+        },
+    };
+
+    if let Some((message, MessagePlacement::Banner)) = message {
+        // over the last row (the update time or the last passage), scrolling like the destinations
+        let last_row = &mut screen[geometry.rows() - 1];
+        last_row.clear();
+        let _ = last_row.push_str(marquee(&message.text, geometry.columns(), marquee_step(state.now)));
+    }
 
-            return; // nothing changed
-        }
-        let mut new_buffer: [heapless::String<20>; 4] = Default::default();
-        let _ = new_buffer[0].push_str(line);
+    for row in screen.iter_mut().take(geometry.rows()) {
+        pad_to_width(row, geometry.columns());
+    }
+    Some(screen)
+}
 
-        if tram_direction_state.next_passages.is_empty() {
-            let _ = new_buffer[1].push_str("Pas de passage dans");
-            let _ = new_buffer[2].push_str("l'heure...");
-        } else {
-            let mut buf: heapless::String<20> = heapless::String::new();
-            for (i, next) in tram_direction_state.next_passages.iter().enumerate() {
-                buf.clear();
-                let _ = write!(new_buffer[i + 1], "{:<17} {:>2}", next.destination, next.relative_arrival);
+fn render_message(message: &str, geometry: LcdGeometry) -> LcdScreen {
+    let mut screen = LcdScreen::default();
+    let mut buffer: heapless::String<80> = heapless::String::new();
+    wrap_text(message, geometry.columns(), &mut buffer);
+    for (row, text) in screen.iter_mut().zip(buffer.split('\n')).take(geometry.rows()) {
+        let _ = row.push_str(text);
+    }
+    screen
+}
+
+// digits of 3 columns by 2 rows, drawn with the blocks of CUSTOM_GLYPHS:
+// `▀` top bar, `▄` bottom bar, `═` both (the middle bar of the digit is the bottom bar of the top row) and `█`
+const BIG_DIGITS: [[&str; 2]; 10] = [
+    ["█▀█", "█▄█"],
+    ["▀█ ", "▄█▄"],
+    ["══█", "█▄▄"],
+    ["══█", "▄▄█"],
+    ["█▄█", "  █"],
+    ["█══", "▄▄█"],
+    ["█══", "█▄█"],
+    ["▀▀█", "  █"],
+    ["█═█", "█▄█"],
+    ["█═█", "▄▄█"],
+];
+
+// idle screen, shown when there are no lines, as a screen of the rotation and in night mode
+// - 20x04: the time in big digits on the first two rows, the date below, the last row is left to the warnings
+// - 16x02: the time and the date in plain text
+// the seconds aren't shown, the screen only changes once a minute
+fn render_clock(local: &LocalTime, geometry: LcdGeometry) -> LcdScreen {
+    let mut screen = LcdScreen::default();
+    let mut date: heapless::String<24> = heapless::String::new();
+    let _ = write!(date, "{} {} {}", local.weekday_name(), local.day, local.month_name());
+    match geometry {
+        LcdGeometry::L2004 => {
+            let digits = [local.hour / 10, local.hour % 10, local.minute / 10, local.minute % 10].map(|d| BIG_DIGITS[d as usize]);
+            for (half, row) in screen.iter_mut().take(2).enumerate() {
+                let _ = write!(row, " {} {} · {} {}", digits[0][half], digits[1][half], digits[2][half], digits[3][half]);
             }
+            let _ = write!(date, " {}", local.year);
+            let _ = write!(screen[2], "{:^20}", date);
         }
-        let _ = write!(
-            new_buffer[3],
-            "{:>20}",
-            tram_direction_state.update_at
-        );
+        LcdGeometry::L1602 => {
+            let mut time: heapless::String<5> = heapless::String::new();
+            let _ = write!(time, "{:02}:{:02}", local.hour, local.minute);
+            let _ = write!(screen[0], "{:^16}", time);
+            let _ = write!(screen[1], "{:^16}", date);
+        }
+    }
+    screen
+}
 
-        self.last_rendered = Some(tram_direction_state.clone());
-        self.last_rendered_line = Some(line.clone());
+// how often the long destinations scroll by one character, the screen has to be rendered at least this often
+pub const MARQUEE_STEP_MS: u64 = 500;
+// number of steps the text stays still at each end
+const MARQUEE_PAUSE_STEPS: u64 = 4;
+
+// part of `text` shown in a column of `width` characters at the given scroll step
+// texts longer than the column scroll one character per step, with a pause at each end, shorter ones don't move
+fn marquee(text: &str, width: usize, step: u64) -> &str {
+    let len = text.chars().count();
+    if len <= width {
+        return text;
+    }
+    let max_offset = (len - width) as u64;
+    let t = step % (max_offset + 2 * MARQUEE_PAUSE_STEPS);
+    let offset = t.saturating_sub(MARQUEE_PAUSE_STEPS).min(max_offset) as usize;
+    let byte_index = |n: usize| text.char_indices().nth(n).map_or(text.len(), |(i, _)| i);
+    &text[byte_index(offset)..byte_index(offset + width)]
+}
 
-        // the true bottleneck is the LCD update
-        // trading CPU for less I2C traffic is worth it
-        let (_, width, _) = self.lcd_screen.get_size_and_offset();
+fn marquee_step(now: Instant) -> u64 {
+    now.as_millis() / MARQUEE_STEP_MS
+}
 
-        for i in 0..4 {
-            pad_to_width(&mut new_buffer[i], (width +1) as usize);
+// layout of the 20x04 LCD screen
+// the remaining minutes and the scrolling of the destinations are derived from `now` on every render,
+// so the same direction state can produce a different screen on each tick
+fn render_direction<const PASSAGES: usize>(
+    line: &str,
+    tram_direction_state: &TramDirectionState<PASSAGES>,
+    now: Instant,
+    clock: Option<&WallClock>,
+) -> LcdScreen {
+    let mut new_buffer = LcdScreen::default();
+    let _ = new_buffer[0].push_str(line);
+
+    match tram_direction_state.freshness(now) {
+        Freshness::NoData => {
+            // the backend stopped publishing a long time ago, showing the passages would be misleading
+            let _ = new_buffer[1].push_str("Pas de donnees");
+            let _ = new_buffer[2].push_str("depuis");
+            let _ = write!(new_buffer[3], "{:>20}", tram_direction_state.update_at);
         }
-
-        for i in 0..4 {
-            if self.display_buffer[i] != new_buffer[i] {
-                self.lcd_screen.set_cursor(i as u8, 0).await;
-                self.lcd_screen.print(&new_buffer[i]).await;
-                self.display_buffer[i] = new_buffer[i].clone();
+        freshness => {
+            let mut row = 1;
+            for (next, remaining_minutes) in tram_direction_state.remaining_passages(now, clock).take(2) {
+                // longer destinations scroll, the minutes stay in place
+                let destination = marquee(&next.destination, 17, marquee_step(now));
+                let _ = write!(new_buffer[row], "{:<17.17}{}{:>2}", destination, arrival_marker(next.arrival_kind), remaining_minutes);
+                row += 1;
             }
+            if row == 1 {
+                let _ = new_buffer[1].push_str("Pas de passage dans");
+                let _ = new_buffer[2].push_str("l'heure...");
+            }
+            match (freshness, &tram_direction_state.disruption) {
+                // the update time is only worth showing when the data gets old
+                (Freshness::Fresh, Some(disruption)) => {
+                    let _ = new_buffer[3].push_str(marquee(disruption, 20, marquee_step(now)));
+                }
+                _ => {
+                    let warning = if freshness == Freshness::Stale { "ANCIENNES" } else { "" };
+                    let _ = write!(
+                        new_buffer[3],
+                        "{:<12}{:>8}",
+                        warning,
+                        tram_direction_state.update_at
+                    );
+                }
+            }
+        }
+    }
+    new_buffer
+}
+
+// how long each page of the second row stays on the 16x02 screen
+pub const SMALL_PAGE_SECS: u64 = 5;
+
+// scheduled times are marked with a `*` between the destination and the minutes
+// (`~` would be nicer but it's a right arrow in the HD44780 A00 ROM)
+fn arrival_marker(arrival_kind: ArrivalKind) -> char {
+    match arrival_kind {
+        ArrivalKind::Realtime => ' ',
+        ArrivalKind::Scheduled => '*',
+    }
+}
+
+// layout of the 16x02 LCD screen, there is only room for the essential:
+// - row 0: line name and next passage
+// - row 1: pages cycling every SMALL_PAGE_SECS between the following passages and the update time
+// the page is derived from `now` like the countdown, so the renderer doesn't need to keep any state
+fn render_direction_small<const PASSAGES: usize>(
+    line: &str,
+    tram_direction_state: &TramDirectionState<PASSAGES>,
+    now: Instant,
+    clock: Option<&WallClock>,
+) -> LcdScreen {
+    let mut new_buffer = LcdScreen::default();
+
+    let freshness = tram_direction_state.freshness(now);
+    if freshness == Freshness::NoData {
+        let _ = write!(new_buffer[0], "{:.16}", line);
+        let _ = new_buffer[1].push_str("Pas de donnees");
+        return new_buffer;
+    }
+
+    let mut passages = tram_direction_state.remaining_passages(now, clock);
+    let Some((first, first_minutes)) = passages.next() else {
+        let _ = write!(new_buffer[0], "{:.16}", line);
+        let _ = new_buffer[1].push_str("Pas de passage");
+        return new_buffer;
+    };
+
+    // the line name is cut to leave at least 6 characters to the destination
+    let line = &line[..line.char_indices().nth(6).map_or(line.len(), |(i, _)| i)];
+    let destination_width = 16 - 3 - line.chars().count() - 1;
+    let _ = write!(
+        new_buffer[0],
+        "{} {:<width$.width$}{}{:>2}",
+        line,
+        marquee(&first.destination, destination_width, marquee_step(now)),
+        arrival_marker(first.arrival_kind),
+        first_minutes,
+        width = destination_width
+    );
+
+    // one page per following passage, and the update time last (there is at least one passage, so room for it)
+    // or the disruption instead of the update time while the data is fresh
+    // the destinations of the pages start scrolling when the page is shown
+    let page_step = (now.as_millis() % (SMALL_PAGE_SECS * 1000)) / MARQUEE_STEP_MS;
+    let mut pages: heapless::Vec<LcdRow, PASSAGES> = heapless::Vec::new();
+    for (next, remaining_minutes) in passages {
+        let mut page = LcdRow::new();
+        let destination = marquee(&next.destination, 13, page_step);
+        let _ = write!(page, "{:<13.13}{}{:>2}", destination, arrival_marker(next.arrival_kind), remaining_minutes);
+        let _ = pages.push(page);
+    }
+    let mut page = LcdRow::new();
+    match (freshness, &tram_direction_state.disruption) {
+        (Freshness::Fresh, Some(disruption)) => {
+            let _ = page.push_str(marquee(disruption, 16, page_step));
+        }
+        _ => {
+            let label = if freshness == Freshness::Stale { "ANCIEN" } else { "MAJ" };
+            let _ = write!(page, "{:<6}{:>10.10}", label, tram_direction_state.update_at);
         }
     }
+    let _ = pages.push(page);
+
+    let page = (now.as_secs() / SMALL_PAGE_SECS) as usize % pages.len();
+    new_buffer[1] = pages.swap_remove(page);
+    new_buffer
+}
+
+// character ROM of the HD44780, printed on the controller (HD44780UA00 / HD44780UA02)
+// most of the I2C modules sold are A00 (japanese), which has almost no accented characters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LcdRom {
+    A00,
+    A02, // european, the upper half follows Latin-1 for the accented letters
+}
+
+// code of `c` in the ROM, if there is one
+fn rom_code(rom: LcdRom, c: char) -> Option<u8> {
+    match rom {
+        LcdRom::A00 => match c {
+            'ä' => Some(0xE1),
+            'ñ' => Some(0xEE),
+            'ö' => Some(0xEF),
+            'ü' => Some(0xF5),
+            '°' => Some(0xDF),
+            _ => None,
+        },
+        LcdRom::A02 => matches!(c, 'À'..='ÿ').then_some(c as u8),
+    }
 }
 
-// assume a 20x04 LCD screen is used
-// I feel like 16x02 would be too small anyway
-impl TramDisplay for LcdRenderer<'_> {
-    async fn render<'b>(&'b mut self, state: &'b crate::display::UiState) {
-        self.lcd_screen.set_backlight(state.backlight_on).await;
-        if state.lines.is_empty() {
-            if let Some(message) = &state.current_message {
-                self.lcd_screen.clear().await;
-                let (_, line_width, _) = self.lcd_screen.get_size_and_offset();
-                let mut buffer: heapless::String<80> = heapless::String::new();
-                wrap_text(message, (line_width + 1) as usize, &mut buffer);
-                // esp_println::println!("{}",buffer);
-                self.lcd_screen.print(&buffer).await;
+// 5x8 glyphs of the accented letters used in the stop names, one byte per row (bit 4 is the left pixel)
+// then the blocks of the big digits of the clock
+const CUSTOM_GLYPHS: [(char, [u8; 8]); 20] = [
+    ('é', [0b00010, 0b00100, 0b01110, 0b10001, 0b11111, 0b10000, 0b01110, 0b00000]),
+    ('è', [0b01000, 0b00100, 0b01110, 0b10001, 0b11111, 0b10000, 0b01110, 0b00000]),
+    ('ê', [0b00100, 0b01010, 0b01110, 0b10001, 0b11111, 0b10000, 0b01110, 0b00000]),
+    ('ë', [0b01010, 0b00000, 0b01110, 0b10001, 0b11111, 0b10000, 0b01110, 0b00000]),
+    ('à', [0b01000, 0b00100, 0b01110, 0b00001, 0b01111, 0b10001, 0b01111, 0b00000]),
+    ('â', [0b00100, 0b01010, 0b01110, 0b00001, 0b01111, 0b10001, 0b01111, 0b00000]),
+    ('ç', [0b00000, 0b01110, 0b10000, 0b10000, 0b10001, 0b01110, 0b00100, 0b01100]),
+    ('î', [0b00100, 0b01010, 0b00000, 0b01100, 0b00100, 0b00100, 0b01110, 0b00000]),
+    ('ï', [0b01010, 0b00000, 0b01100, 0b00100, 0b00100, 0b00100, 0b01110, 0b00000]),
+    ('ô', [0b00100, 0b01010, 0b01110, 0b10001, 0b10001, 0b10001, 0b01110, 0b00000]),
+    ('û', [0b00100, 0b01010, 0b00000, 0b10001, 0b10001, 0b10011, 0b01101, 0b00000]),
+    ('ù', [0b01000, 0b00100, 0b00000, 0b10001, 0b10001, 0b10011, 0b01101, 0b00000]),
+    ('É', [0b00010, 0b00100, 0b11111, 0b10000, 0b11110, 0b10000, 0b11111, 0b00000]),
+    ('È', [0b01000, 0b00100, 0b11111, 0b10000, 0b11110, 0b10000, 0b11111, 0b00000]),
+    ('Ê', [0b00100, 0b01010, 0b11111, 0b10000, 0b11110, 0b10000, 0b11111, 0b00000]),
+    ('█', [0b11111; 8]),
+    ('▀', [0b11111, 0b11111, 0b11111, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000]),
+    ('▄', [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b11111, 0b11111, 0b11111]),
+    ('═', [0b11111, 0b11111, 0b11111, 0b00000, 0b00000, 0b11111, 0b11111, 0b11111]),
+    ('·', [0b00000, 0b00000, 0b00000, 0b01110, 0b01110, 0b00000, 0b00000, 0b00000]),
+];
+
+pub fn custom_glyph(c: char) -> Option<&'static [u8; 8]> {
+    CUSTOM_GLYPHS.iter().find(|(glyph_char, _)| *glyph_char == c).map(|(_, glyph)| glyph)
+}
+
+pub const CGRAM_SLOTS: usize = 8;
+
+// maps the characters of the screen to the codes sent to the LCD:
+// ASCII as is, then the ROM, then one of the 8 custom glyphs (CGRAM), and the letter without its accent as a last resort
+// the custom glyphs are allocated for each screen, so up to 8 different accented letters can be shown at once
+#[derive(Debug, Clone)]
+pub struct CharMap {
+    rom: LcdRom,
+    slots: [Option<char>; CGRAM_SLOTS],
+}
+
+impl CharMap {
+    pub fn new(rom: LcdRom) -> Self {
+        CharMap { rom, slots: [None; CGRAM_SLOTS] }
+    }
+
+    fn needs_slot(&self, c: char) -> bool {
+        !c.is_ascii() && rom_code(self.rom, c).is_none() && custom_glyph(c).is_some()
+    }
+
+    // make room for the characters of the new screen, returns the slots to load in the CGRAM
+    // the characters already loaded keep their slot: changing a slot changes it everywhere on the screen,
+    // including the rows that won't be rewritten
+    pub fn allocate(&mut self, rows: &[LcdRow]) -> heapless::Vec<(u8, char), CGRAM_SLOTS> {
+        let mut needed: heapless::Vec<char, CGRAM_SLOTS> = heapless::Vec::new();
+        for c in rows.iter().flat_map(|row| row.chars()) {
+            if self.needs_slot(c) && !needed.contains(&c) && needed.push(c).is_err() {
+                break; // more than 8 different accented letters, the others fall back to the plain letters
+            }
+        }
+
+        for slot in self.slots.iter_mut() {
+            if slot.is_some_and(|c| !needed.contains(&c)) {
+                *slot = None;
             }
-            return;
         }
 
-        let Some(line) = state.lines.get(state.current_line) else { return };
-        if let Some(directions) = line.directions.get(state.current_direction_id) {
-            self.render_line(&line.line,directions).await;
+        let mut loads = heapless::Vec::new();
+        for c in needed {
+            if self.slots.contains(&Some(c)) {
+                continue;
+            }
+            if let Some(free) = self.slots.iter().position(Option::is_none) {
+                self.slots[free] = Some(c);
+                let _ = loads.push((free as u8, c));
+            }
         }
+        loads
     }
-    
-    async fn healthcheck<'a>(&'a mut self) {
-        self.lcd_screen.check_connected().await;
+
+    // code to send to the LCD for `c`
+    pub fn code(&self, c: char) -> u8 {
+        if c.is_ascii() {
+            return c as u8;
+        }
+        if let Some(code) = rom_code(self.rom, c) {
+            return code;
+        }
+        match self.slots.iter().position(|slot| *slot == Some(c)) {
+            Some(slot) => slot as u8,
+            None => font::ascii_fallback(c) as u8,
+        }
+    }
+
+    // character actually shown by the LCD for `c`, used by the renderer to know what is on the screen and by the simulator
+    pub fn preview(&self, c: char) -> char {
+        if c.is_ascii() || rom_code(self.rom, c).is_some() || self.slots.contains(&Some(c)) {
+            c
+        } else {
+            font::ascii_fallback(c)
+        }
     }
 }
 
-// could be more generic, but this is good enough for our use case, and we can always refactor later if needed
-pub enum LcdGeometry {
-    L1602, // 16 characters, 2 lines
-    L2004, // 20 characters, 4 lines
+// the HD44780 driver itself, it only needs an I2C bus implementing the embedded-hal traits
+// (on the ESP32 it's a device on the shared esp-hal bus, in the tests a mock recording the writes)
+
+pub struct LcdRenderer<B: I2c> {
+    lcd_screen: Lcd<B>, // handle to the LCD screen, used to send commands and data to the LCD
+    display_buffer: LcdScreen, // we keep a buffer of the currently displayed content on the LCD to minimize the number of updates, which is slow 
+}
+
+impl<B: I2c> LcdRenderer<B> {
+    pub fn new(lcd_screen: Lcd<B>) -> Self {
+        LcdRenderer { 
+            lcd_screen,
+            display_buffer: LcdScreen::default(),
+        }
+    }
+}
+
+// columns of the first and last characters of `new` that differ from `old`, None if the rows are the same
+fn changed_span(old: &str, new: &str) -> Option<(usize, usize)> {
+    let mut old_chars = old.chars();
+    let mut span = None;
+    for (i, c) in new.chars().enumerate() {
+        if old_chars.next() != Some(c) {
+            span = Some((span.map_or(i, |(first, _)| first), i));
+        }
+    }
+    span
+}
+
+impl<B: I2c> TramDisplay for LcdRenderer<B> {
+    async fn render<'b, const LINES: usize, const DIRECTIONS: usize, const PASSAGES: usize>(
+        &'b mut self,
+        state: &'b UiState<LINES, DIRECTIONS, PASSAGES>,
+    ) {
+        // the screen is rendered often for the scrolling, don't resend the backlight state every time
+        if self.lcd_screen.backlight() != state.backlight_on {
+            self.lcd_screen.set_backlight(state.backlight_on).await;
+        }
+        let geometry = self.lcd_screen.geometry();
+        let Some(new_buffer) = render_screen(state, geometry) else { return };
+        self.lcd_screen.load_glyphs(&new_buffer[..geometry.rows()]).await;
+
+        // the true bottleneck is the LCD update
+        // trading CPU for less I2C traffic is worth it, only the characters between the first and the last change are rewritten
+        for (i, row) in new_buffer.iter().enumerate().take(geometry.rows()) {
+            // compare what the LCD will show, not what was asked: a letter that fell back to the plain one for lack of
+            // CGRAM slot must be rewritten once it gets a slot, even if the text of the row didn't change
+            let row = &self.lcd_screen.shown(row);
+            if let Some((first, last)) = changed_span(&self.display_buffer[i], row) {
+                let byte_index = |n: usize| row.char_indices().nth(n).map_or(row.len(), |(i, _)| i);
+                self.lcd_screen.set_cursor(i as u8, first as u8).await;
+                self.lcd_screen.print(&row[byte_index(first)..byte_index(last + 1)]).await;
+                self.display_buffer[i] = row.clone();
+            }
+        }
+    }
+
+    async fn healthcheck(&mut self) {
+        self.lcd_screen.check_connected().await;
+    }
 }
 
 mod lcd_bits {
@@ -160,17 +513,19 @@ mod lcd_bits {
 
 mod lcd_commands {
     pub const LCD_SETDDRAMADDR: u8 = 0x80;
+    pub const LCD_SETCGRAMADDR: u8 = 0x40;
     pub const LCD_CLEARDISPLAY: u8 = 0x01;
 }
 
-pub struct Lcd<'a> {
-    bus: &'a Mutex<CriticalSectionRawMutex, Option<I2c<'static, Blocking>>>,
+pub struct Lcd<B: I2c> {
+    bus: B,
     i2c_addr: u8,
     geom: LcdGeometry,
     curr_row: u8,
     curr_col: u8,
     backlight_on: bool,
-    is_connected: bool
+    is_connected: bool,
+    charmap: CharMap, // which custom glyphs are in the CGRAM
 }
 
 // Source: https://cdn.sparkfun.com/assets/9/5/f/7/b/HD44780.pdf
@@ -178,14 +533,15 @@ pub struct Lcd<'a> {
 // D7 D6 D5 D4 BL EN RW RS
 // some things could be enhanced here in the future, probably
 // Doesn't contain the rendering logic, just the low-level commands to control the LCD (used by the LcdRenderer to render the UI state)
-impl<'a> Lcd<'a> {
+impl<B: I2c> Lcd<B> {
 
     pub fn new(
-        bus: &'a Mutex<CriticalSectionRawMutex, Option<I2c<'static, Blocking>>>,
+        bus: B,
         i2c_addr: u8,
-        geom: LcdGeometry
+        geom: LcdGeometry,
+        rom: LcdRom,
     ) -> Self {
-        Self { i2c_addr, bus, geom, curr_row: 0, curr_col: 0, backlight_on: true, is_connected: true }
+        Self { i2c_addr, bus, geom, curr_row: 0, curr_col: 0, backlight_on: true, is_connected: true, charmap: CharMap::new(rom) }
     }
 
     // set the LCD in the desired mode and initialize it, needs to be called before any other command
@@ -201,6 +557,10 @@ impl<'a> Lcd<'a> {
         self.send(0x0C, 0).await; // display ON
     }
 
+    pub fn geometry(&self) -> LcdGeometry {
+        self.geom
+    }
+
     fn get_size_and_offset(&self) -> (u8, u8, &[u8]) {
         match self.geom {
             LcdGeometry::L1602 => (1, 15, &[0x00, 0x40]),
@@ -220,6 +580,10 @@ impl<'a> Lcd<'a> {
         self.send(value, 0).await;
     }
 
+    pub fn backlight(&self) -> bool {
+        self.backlight_on
+    }
+
     pub async fn set_backlight(&mut self, on: bool) {
         self.backlight_on = on;
         // to update the backlight state, we need to send a command (it can be a no-op command since the backlight state is sent with every command)
@@ -246,8 +610,33 @@ impl<'a> Lcd<'a> {
         self.set_cursor(0,0).await;
     }
 
+    // non-ASCII characters are sent as their ROM code or custom glyph, see `load_glyphs`
     pub async fn putc(&mut self, c: char) {
-        self.send(c as u8, 1).await;
+        self.send(self.charmap.code(c), 1).await;
+    }
+
+    // write a 5x8 glyph (one byte per row) in one of the 8 CGRAM slots, it's then printed with the code `slot`
+    pub async fn create_char(&mut self, slot: u8, glyph: &[u8; 8]) {
+        self.command(lcd_commands::LCD_SETCGRAMADDR | ((slot & 0x07) << 3)).await;
+        for row in glyph {
+            self.send(*row, 1).await;
+        }
+        // the following writes would go to the CGRAM otherwise
+        self.set_cursor(self.curr_row, self.curr_col).await;
+    }
+
+    // `row` as the LCD shows it with the glyphs currently loaded
+    pub fn shown(&self, row: &LcdRow) -> LcdRow {
+        row.chars().map(|c| self.charmap.preview(c)).collect()
+    }
+
+    // load the custom glyphs of the accented letters in `rows`, to call before printing them
+    pub async fn load_glyphs(&mut self, rows: &[LcdRow]) {
+        for (slot, c) in self.charmap.allocate(rows) {
+            if let Some(glyph) = custom_glyph(c) {
+                self.create_char(slot, glyph).await;
+            }
+        }
     }
 
     async fn send(&mut self, value: u8, mode: u8) {
@@ -259,38 +648,34 @@ impl<'a> Lcd<'a> {
 
     // D7 D6 D5 D4 BL EN RW RS
     async fn write_4_bits(&mut self, value: u8) {
-        let mut guard = self.bus.lock().await;
-        let i2c = guard.as_mut().expect("I2C not initialized");
-        self.write_i2c(i2c, value);
-        self.pulse_enable(i2c, value).await;
+        self.write_i2c(value).await;
+        self.pulse_enable(value).await;
     }
 
     async fn check_connected(&mut self) {
-        let mut guard = self.bus.lock().await;
-        let i2c = guard.as_mut().expect("I2C not initialized");
-        if i2c.write(self.i2c_addr, &[]).is_ok() {
-            // esp_println::println!("I2C device found at 0x{:02X}", self.i2c_addr);
+        if self.bus.write(self.i2c_addr, &[]).await.is_ok() {
+            // log!("I2C device found at 0x{:02X}", self.i2c_addr);
             self.is_connected = true;
         } else {
-            esp_println::println!("I2C device not found at 0x{:02X}", self.i2c_addr);
+            log!("I2C device not found at 0x{:02X}", self.i2c_addr);
             self.is_connected = false;
         }
     }
 
-    fn write_i2c(&mut self, i2c_bus: &mut I2c<'_, Blocking>, data: u8) {
+    async fn write_i2c(&mut self, data: u8) {
         if !self.is_connected { return };
-        let result = i2c_bus.write(self.i2c_addr, &[data]);
+        let result = self.bus.write(self.i2c_addr, &[data]).await;
 
         if result.is_err() {
-            esp_println::println!("Error when sending");
+            log!("Error when sending");
             self.is_connected = false;
         }
     }
 
-    async fn pulse_enable(&mut self, i2c_bus: &mut I2c<'_, Blocking>, data: u8) {
-        self.write_i2c(i2c_bus, data | lcd_bits::EN);
+    async fn pulse_enable(&mut self, data: u8) {
+        self.write_i2c(data | lcd_bits::EN).await;
         Timer::after(Duration::from_micros(1)).await;
-        self.write_i2c(i2c_bus, data & !lcd_bits::EN);
+        self.write_i2c(data & !lcd_bits::EN).await;
         Timer::after(Duration::from_micros(50)).await;
     }
 
@@ -305,3 +690,331 @@ impl<'a> Lcd<'a> {
         Timer::after(Duration::from_millis(1)).await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::{Severity, Staleness, UiCommand, apply_ui_command};
+    use crate::protocol;
+    use embedded_hal_async::i2c::{ErrorKind, ErrorType, Operation};
+
+    const ADDR: u8 = 0x27;
+    const BL: u8 = lcd_bits::BL;
+    const RS: u8 = 0b0000_0001;
+
+    // records every byte written to the PCF8574
+    #[derive(Default)]
+    struct MockBus {
+        writes: std::vec::Vec<u8>,
+        probes: usize, // empty writes, used to check that the device is there
+        failing: bool,
+    }
+
+    impl ErrorType for MockBus {
+        type Error = ErrorKind;
+    }
+
+    impl I2c for MockBus {
+        async fn transaction(&mut self, address: u8, operations: &mut [Operation<'_>]) -> Result<(), Self::Error> {
+            assert_eq!(address, ADDR);
+            if self.failing {
+                return Err(ErrorKind::Other);
+            }
+            for operation in operations {
+                if let Operation::Write(data) = operation {
+                    if data.is_empty() {
+                        self.probes += 1;
+                    }
+                    self.writes.extend_from_slice(data);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn mock_lcd(geometry: LcdGeometry) -> Lcd<MockBus> {
+        Lcd::new(MockBus::default(), ADDR, geometry, LcdRom::A00)
+    }
+
+    fn rows(texts: &[&str]) -> std::vec::Vec<LcdRow> {
+        texts.iter().map(|text| LcdRow::try_from(*text).unwrap()).collect()
+    }
+
+    // every nibble is written 3 times: as is, with EN high, then with EN low to latch it
+    // returns the latched nibbles (with the BL/RS bits), after checking the pulses
+    fn nibbles(writes: &[u8]) -> std::vec::Vec<u8> {
+        assert_eq!(writes.len() % 3, 0);
+        writes
+            .chunks(3)
+            .map(|pulse| {
+                assert_eq!(pulse, [pulse[0], pulse[0] | lcd_bits::EN, pulse[0] & !lcd_bits::EN]);
+                pulse[0]
+            })
+            .collect()
+    }
+
+    // bytes sent with `send`, as (value, BL/RS bits)
+    fn sent(writes: &[u8]) -> std::vec::Vec<(u8, u8)> {
+        nibbles(writes)
+            .chunks(2)
+            .map(|pair| {
+                assert_eq!(pair[0] & 0x0F, pair[1] & 0x0F);
+                ((pair[0] & 0xF0) | (pair[1] >> 4), pair[0] & 0x0F)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn init_sequence() {
+        let mut lcd = mock_lcd(LcdGeometry::L2004);
+        embassy_futures::block_on(lcd.init());
+
+        let writes = &lcd.bus.writes;
+        // 3 times "8 bits mode" then "4 bits mode", as single nibbles (without the backlight bit)
+        assert_eq!(nibbles(&writes[..12]), [0x30, 0x30, 0x30, 0x20]);
+        assert_eq!(
+            sent(&writes[12..]),
+            [(0x28, BL), (0x08, BL), (0x01, BL), (0x06, BL), (0x0C, BL)]
+        );
+    }
+
+    #[test]
+    fn cursor_positions() {
+        let mut lcd = mock_lcd(LcdGeometry::L2004);
+        embassy_futures::block_on(async {
+            lcd.set_cursor(0, 0).await;
+            lcd.set_cursor(1, 5).await;
+            lcd.set_cursor(2, 0).await;
+            lcd.set_cursor(3, 19).await;
+        });
+        assert_eq!(sent(&lcd.bus.writes), [(0x80, BL), (0xC5, BL), (0x94, BL), (0xE7, BL)]);
+
+        let mut lcd = mock_lcd(LcdGeometry::L1602);
+        embassy_futures::block_on(lcd.set_cursor(1, 15));
+        assert_eq!(sent(&lcd.bus.writes), [(0xCF, BL)]);
+    }
+
+    #[test]
+    fn print_sends_data_then_moves_the_cursor() {
+        let mut lcd = mock_lcd(LcdGeometry::L2004);
+        embassy_futures::block_on(async {
+            lcd.set_cursor(1, 0).await;
+            lcd.print("Hi\nA").await;
+        });
+        assert_eq!(
+            sent(&lcd.bus.writes),
+            [
+                (0xC0, BL),
+                (b'H', BL | RS),
+                (0xC1, BL),
+                (b'i', BL | RS),
+                (0xC2, BL),
+                (0x94, BL), // new line
+                (b'A', BL | RS),
+                (0x95, BL),
+            ]
+        );
+    }
+
+    #[test]
+    fn backlight_bit() {
+        let mut lcd = mock_lcd(LcdGeometry::L2004);
+        embassy_futures::block_on(async {
+            lcd.set_backlight(false).await;
+            lcd.putc('x').await;
+            lcd.set_backlight(true).await;
+        });
+        assert_eq!(sent(&lcd.bus.writes), [(0x00, 0), (b'x', RS), (0x00, BL)]);
+    }
+
+    #[test]
+    fn loads_custom_glyphs() {
+        let mut lcd = mock_lcd(LcdGeometry::L2004);
+        embassy_futures::block_on(async {
+            lcd.set_cursor(2, 4).await;
+            lcd.create_char(3, custom_glyph('é').unwrap()).await;
+        });
+        let sent = sent(&lcd.bus.writes);
+        assert_eq!(sent[1], (0x58, BL)); // CGRAM address of slot 3
+        let glyph = sent[2..10].to_vec();
+        assert_eq!(glyph, custom_glyph('é').unwrap().map(|row| (row, BL | RS)));
+        assert_eq!(sent[10..], [(0x98, BL)]); // back to row 2, column 4
+    }
+
+    #[test]
+    fn prints_accented_letters() {
+        let mut lcd = mock_lcd(LcdGeometry::L2004);
+        embassy_futures::block_on(async {
+            lcd.load_glyphs(&rows(&["Gières", "Université"])).await;
+            lcd.bus.writes.clear();
+            for c in "èéüœ".chars() {
+                lcd.putc(c).await;
+            }
+        });
+        // slots in order of appearance, ü is in the ROM, œ has no glyph
+        assert_eq!(sent(&lcd.bus.writes), [(0, BL | RS), (1, BL | RS), (0xF5, BL | RS), (b'?', BL | RS)]);
+    }
+
+    #[test]
+    fn glyphs_keep_their_slot_between_screens() {
+        let mut charmap = CharMap::new(LcdRom::A00);
+        assert_eq!(charmap.allocate(&rows(&["Gières", "Université"])).as_slice(), [(0, 'è'), (1, 'é')]);
+        // è is still there, é is replaced
+        assert_eq!(charmap.allocate(&rows(&["Gières", "Château"])).as_slice(), [(1, 'â')]);
+        assert_eq!(charmap.code('è'), 0);
+        assert_eq!(charmap.code('â'), 1);
+        assert_eq!(charmap.code('é'), b'e');
+        assert!(charmap.allocate(&rows(&["Gières", "Château"])).is_empty());
+    }
+
+    #[test]
+    fn falls_back_to_plain_letters() {
+        let mut charmap = CharMap::new(LcdRom::A00);
+        let loads = charmap.allocate(&rows(&["éèêëàâçîïô", "ûù"]));
+        assert_eq!(loads.len(), CGRAM_SLOTS);
+        // only the first 8 letters got a slot
+        assert_eq!(charmap.preview('î'), 'î');
+        assert_eq!(charmap.preview('ï'), 'i');
+        assert_eq!(charmap.preview('ô'), 'o');
+        assert_eq!(charmap.code('û'), b'u');
+        assert_eq!(charmap.code('’'), b'\'');
+
+        // the european ROM has them all
+        let mut charmap = CharMap::new(LcdRom::A02);
+        assert!(charmap.allocate(&rows(&["éèêëàâçîïô", "ûù"])).is_empty());
+        assert_eq!(charmap.code('é'), 0xE9);
+        assert_eq!(charmap.code('Ê'), 0xCA);
+    }
+
+    #[test]
+    fn rewrites_a_plain_letter_once_it_gets_a_slot() {
+        let mut state: UiState = UiState::new(Staleness::default(), Instant::from_secs(0));
+        let error = |text: &str| UiCommand::UpdateMessage {
+            text: heapless::String::try_from(text).unwrap(),
+            severity: Severity::Error,
+            expires_after: None,
+        };
+        let mut renderer = LcdRenderer::new(mock_lcd(LcdGeometry::L2004));
+
+        // 9 accented letters, the ï is shown as a plain i
+        apply_ui_command(&mut state, error("éèêëàâçî ï"), Instant::from_secs(0));
+        embassy_futures::block_on(renderer.render(&state));
+        renderer.lcd_screen.bus.writes.clear();
+
+        // the é is gone, its slot goes to the ï which must be rewritten even though its cell has the same text
+        apply_ui_command(&mut state, error("eèêëàâçî ï"), Instant::from_secs(0));
+        embassy_futures::block_on(renderer.render(&state));
+        let sent = sent(&renderer.lcd_screen.bus.writes);
+        let cursor = sent.iter().position(|&byte| byte == (0x80, BL)).unwrap(); // row 0, column 0
+        let printed: std::vec::Vec<u8> =
+            sent[cursor..].iter().filter(|(_, flags)| flags & RS != 0).map(|(value, _)| *value).collect();
+        assert_eq!(printed, [b'e', 1, 2, 3, 4, 5, 6, 7, b' ', 0]);
+    }
+
+    #[test]
+    fn marquee_pauses_at_each_end() {
+        let text = "Universite - Condillac"; // 22 characters, 5 more than the column
+        let windows: std::vec::Vec<_> = (0..15).map(|step| marquee(text, 17, step)).collect();
+        assert_eq!(windows[..5], ["Universite - Cond"; 5]);
+        assert_eq!(windows[5], "niversite - Condi");
+        assert_eq!(windows[8], "ersite - Condilla");
+        assert_eq!(windows[9..13], ["rsite - Condillac"; 4]);
+        assert_eq!(windows[13], "Universite - Cond"); // back to the start
+        assert_eq!(marquee("Gières", 17, 7), "Gières");
+        assert_eq!(marquee("Université", 6, 5), "nivers");
+    }
+
+    #[test]
+    fn only_rewrites_the_changed_characters() {
+        let mut state: UiState = UiState::new(Staleness::default(), Instant::from_secs(0));
+        let (update, _) = protocol::parse_message("next-tramway/line/C/1", "Tram C\nUniversite - Condillac|8|R\n14:35:10").unwrap();
+        apply_ui_command(&mut state, update, Instant::from_secs(0));
+
+        let mut renderer = LcdRenderer::new(mock_lcd(LcdGeometry::L2004));
+        embassy_futures::block_on(renderer.render(&state));
+        renderer.lcd_screen.bus.writes.clear();
+
+        // first scroll step: only the destination moves, the minutes and the other rows are left alone
+        state.now = Instant::from_millis(MARQUEE_STEP_MS * (MARQUEE_PAUSE_STEPS + 1));
+        embassy_futures::block_on(renderer.render(&state));
+        let sent = sent(&renderer.lcd_screen.bus.writes);
+        assert_eq!(sent[0], (0xC0, BL)); // row 1, column 0
+        let printed: std::string::String =
+            sent.iter().filter(|(_, flags)| flags & RS != 0).map(|(value, _)| *value as char).collect();
+        assert_eq!(printed, "niversite - Condi");
+
+        renderer.lcd_screen.bus.writes.clear();
+        embassy_futures::block_on(renderer.render(&state));
+        assert!(renderer.lcd_screen.bus.writes.is_empty());
+    }
+
+    #[test]
+    fn warnings_replace_the_last_row() {
+        let mut state: UiState = UiState::new(Staleness::default(), Instant::from_secs(0));
+        let (update, _) = protocol::parse_message("next-tramway/line/C/1", "Tram C\nGieres|3|R\n14:35:10").unwrap();
+        apply_ui_command(&mut state, update, Instant::from_secs(0));
+        let warning = UiCommand::UpdateMessage {
+            text: heapless::String::try_from("Wifi: signal faible").unwrap(),
+            severity: Severity::Warning,
+            expires_after: Some(Duration::from_secs(30)),
+        };
+        apply_ui_command(&mut state, warning, Instant::from_secs(0));
+
+        let screen = render_screen(&state, LcdGeometry::L2004).unwrap();
+        assert_eq!(screen[0], "Tram C              ");
+        assert_eq!(screen[3], "Wifi: signal faible ");
+
+        // once expired, the timestamp is back, and the screen is blanked if there is nothing else to show
+        state.now = Instant::from_secs(30);
+        assert!(render_screen(&state, LcdGeometry::L2004).unwrap()[3].ends_with("14:35:10"));
+        state.lines.clear();
+        assert!(render_screen(&state, LcdGeometry::L2004).unwrap().iter().all(|row| row.trim().is_empty()));
+    }
+
+    #[test]
+    fn big_digit_clock() {
+        let mut state: UiState = UiState::new(Staleness::default(), Instant::from_secs(0));
+        // 2025-12-04 09:27 in Paris
+        apply_ui_command(&mut state, UiCommand::SetTime { unix_secs: 1_764_836_820 }, Instant::from_secs(0));
+
+        let screen = render_screen(&state, LcdGeometry::L2004).unwrap();
+        assert_eq!(screen[0], " █▀█ █═█ · ══█ ▀▀█  ");
+        assert_eq!(screen[1], " █▄█ ▄▄█ · █▄▄   █  ");
+        assert_eq!(screen[2], "  jeu. 4 déc. 2025  ");
+        assert_eq!(screen[3].trim(), "");
+        let screen = render_screen(&state, LcdGeometry::L1602).unwrap();
+        assert_eq!(screen[..2], ["     09:27      ", "  jeu. 4 déc.   "]);
+
+        // 4 blocks, the dot and the é: all of them fit in the CGRAM
+        let mut charmap = CharMap::new(LcdRom::A00);
+        let screen = render_screen(&state, LcdGeometry::L2004).unwrap();
+        assert_eq!(charmap.allocate(&screen).len(), 6);
+        assert!(screen.iter().flat_map(|row| row.chars()).all(|c| charmap.preview(c) == c));
+    }
+
+    #[test]
+    fn changed_spans() {
+        assert_eq!(changed_span("Tram C  ", "Tram C  "), None);
+        assert_eq!(changed_span("Gare  3", "Gare  2"), Some((6, 6)));
+        assert_eq!(changed_span("abcdef", "xbcdey"), Some((0, 5)));
+        assert_eq!(changed_span("", "Tram"), Some((0, 3)));
+        assert_eq!(changed_span("Gières", "Gieres"), Some((2, 2)));
+    }
+
+    #[test]
+    fn stops_writing_until_the_lcd_is_back() {
+        let mut lcd = mock_lcd(LcdGeometry::L2004);
+        lcd.bus.failing = true;
+        embassy_futures::block_on(lcd.putc('a'));
+        assert!(!lcd.is_connected);
+
+        lcd.bus.failing = false;
+        embassy_futures::block_on(lcd.putc('b'));
+        assert!(lcd.bus.writes.is_empty());
+
+        embassy_futures::block_on(lcd.check_connected());
+        assert_eq!(lcd.bus.probes, 1);
+        embassy_futures::block_on(lcd.putc('c'));
+        assert_eq!(sent(&lcd.bus.writes), [(b'c', BL | RS)]);
+    }
+}
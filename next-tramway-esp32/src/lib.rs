@@ -1,4 +1,28 @@
-#![no_std]
-pub mod lcd;
-pub mod display;
+#![cfg_attr(not(test), no_std)]
+// the e-paper driver is tied to esp-hal, so it's only built for the ESP32 target
+// everything else is hardware independent and can be tested on the host (see `cargo test-host`)
+
+// esp_println only exists on the ESP32, the logs are dropped on the host
+macro_rules! log {
+    ($($arg:tt)*) => {{
+        #[cfg(target_arch = "riscv32")]
+        esp_println::println!($($arg)*);
+        #[cfg(not(target_arch = "riscv32"))]
+        let _ = format_args!($($arg)*);
+    }};
+}
 
+pub mod binary;
+pub mod canvas;
+pub mod clock;
+pub mod diagnostics;
+pub mod display;
+pub mod epaper;
+pub mod font;
+pub mod input;
+pub mod json;
+pub mod lcd;
+pub mod oled;
+pub mod protocol;
+pub mod sim;
+pub mod sntp;
@@ -0,0 +1,455 @@
+// 128x64 monochrome OLED (SSD1306 or SH1106 over I2C)
+// The UI is first drawn into a framebuffer (hardware independent, tested on the host),
+// then only the 8 pixel high pages that changed since the last flush are sent to the screen
+
+use crate::canvas::Canvas;
+
+pub const WIDTH: usize = 128;
+pub const HEIGHT: usize = 64;
+pub const PAGES: usize = HEIGHT / 8;
+
+// both controllers share the same command set for what we use, the SH1106 has 132 columns of RAM
+// and the 128 visible ones start at column 2
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OledController {
+    Ssd1306,
+    Sh1106,
+}
+
+// same memory layout as the controllers: 8 pages of 128 columns, bit 0 of a column is the top pixel of the page
+#[derive(Clone, PartialEq)]
+pub struct Framebuffer {
+    pages: [[u8; WIDTH]; PAGES],
+}
+
+impl Default for Framebuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Framebuffer {
+    pub const fn new() -> Self {
+        Framebuffer { pages: [[0; WIDTH]; PAGES] }
+    }
+
+    pub fn page(&self, page: usize) -> &[u8; WIDTH] {
+        &self.pages[page]
+    }
+
+    pub fn pixel(&self, x: u16, y: u16) -> bool {
+        let (x, y) = (x as usize, y as usize);
+        if x >= WIDTH || y >= HEIGHT {
+            return false;
+        }
+        self.pages[y / 8][x] & (1 << (y % 8)) != 0
+    }
+}
+
+impl Canvas for Framebuffer {
+    fn width(&self) -> u16 {
+        WIDTH as u16
+    }
+
+    fn height(&self) -> u16 {
+        HEIGHT as u16
+    }
+
+    fn set_pixel(&mut self, x: u16, y: u16, on: bool) {
+        let (x, y) = (x as usize, y as usize);
+        if x >= WIDTH || y >= HEIGHT {
+            return;
+        }
+        if on {
+            self.pages[y / 8][x] |= 1 << (y % 8);
+        } else {
+            self.pages[y / 8][x] &= !(1 << (y % 8));
+        }
+    }
+
+    fn clear(&mut self) {
+        self.pages = [[0; WIDTH]; PAGES];
+    }
+}
+
+pub use driver::{Oled, OledRenderer};
+
+// like the LCD, generic over the I2C bus: an `I2cDevice` of the shared bus on the ESP32, a mock in the tests
+mod driver {
+    use embedded_hal_async::i2c::I2c;
+
+    use super::{Framebuffer, OledController, PAGES, WIDTH};
+    use crate::canvas::render_state;
+    use crate::display::{TramDisplay, UiState};
+
+    mod oled_commands {
+        pub const CONTROL_COMMAND: u8 = 0x00; // the following bytes are commands
+        pub const CONTROL_DATA: u8 = 0x40; // the following bytes are written in the display RAM
+        pub const DISPLAY_OFF: u8 = 0xAE;
+        pub const DISPLAY_ON: u8 = 0xAF;
+        pub const SET_PAGE: u8 = 0xB0;
+        pub const SET_LOW_COLUMN: u8 = 0x00;
+        pub const SET_HIGH_COLUMN: u8 = 0x10;
+    }
+
+    pub struct Oled<B: I2c> {
+        bus: B,
+        i2c_addr: u8,
+        controller: OledController,
+        is_connected: bool,
+    }
+
+    // Source: SSD1306 and SH1106 datasheets
+    // we only use the page addressing mode, which is the default of the SSD1306 and the only mode of the SH1106
+    impl<B: I2c> Oled<B> {
+        pub fn new(bus: B, i2c_addr: u8, controller: OledController) -> Self {
+            Self { bus, i2c_addr, controller, is_connected: true }
+        }
+
+        // needs to be called before any other command
+        pub async fn init(&mut self) {
+            self.commands(&[
+                oled_commands::DISPLAY_OFF,
+                0xD5, 0x80, // clock divide ratio
+                0xA8, 0x3F, // multiplex ratio, 64 rows
+                0xD3, 0x00, // display offset
+                0x40,       // start line 0
+                0xA1,       // segment remap, column 127 is SEG0
+                0xC8,       // COM scan direction, remapped
+                0xDA, 0x12, // COM pins configuration
+                0x81, 0xCF, // contrast
+                0xD9, 0xF1, // pre-charge period
+                0xDB, 0x40, // VCOMH deselect level
+                0xA4,       // display follows the RAM content
+                0xA6,       // normal (not inverted) display
+            ]).await;
+            match self.controller {
+                OledController::Ssd1306 => self.commands(&[0x8D, 0x14]).await, // enable the charge pump
+                OledController::Sh1106 => self.commands(&[0xAD, 0x8B]).await, // enable the DC-DC converter
+            }
+            self.commands(&[oled_commands::DISPLAY_ON]).await;
+        }
+
+        // the OLED has no backlight, turning the panel off is the closest equivalent
+        pub async fn set_display_on(&mut self, on: bool) {
+            self.commands(&[if on { oled_commands::DISPLAY_ON } else { oled_commands::DISPLAY_OFF }]).await;
+        }
+
+        pub async fn write_page(&mut self, page: usize, data: &[u8; WIDTH]) {
+            let column = match self.controller {
+                OledController::Ssd1306 => 0,
+                OledController::Sh1106 => 2,
+            };
+            self.commands(&[
+                oled_commands::SET_PAGE | page as u8,
+                oled_commands::SET_LOW_COLUMN | (column & 0x0F),
+                oled_commands::SET_HIGH_COLUMN | (column >> 4),
+            ]).await;
+
+            let mut buffer = [oled_commands::CONTROL_DATA; WIDTH + 1];
+            buffer[1..].copy_from_slice(data);
+            self.write_i2c(&buffer).await;
+        }
+
+        async fn commands(&mut self, commands: &[u8]) {
+            let mut buffer = [oled_commands::CONTROL_COMMAND; 32];
+            buffer[1..=commands.len()].copy_from_slice(commands);
+            self.write_i2c(&buffer[..=commands.len()]).await;
+        }
+
+        async fn write_i2c(&mut self, data: &[u8]) {
+            if !self.is_connected { return };
+            if self.bus.write(self.i2c_addr, data).await.is_err() {
+                log!("Error when sending");
+                self.is_connected = false;
+            }
+        }
+
+        async fn check_connected(&mut self) {
+            if self.bus.write(self.i2c_addr, &[]).await.is_ok() {
+                self.is_connected = true;
+            } else {
+                log!("I2C device not found at 0x{:02X}", self.i2c_addr);
+                self.is_connected = false;
+            }
+        }
+    }
+
+    pub struct OledRenderer<B: I2c> {
+        oled: Oled<B>,
+        framebuffer: Framebuffer, // what we want on the screen
+        flushed: Option<Framebuffer>, // what is currently on the screen, None if unknown (e.g. at boot)
+        display_on: bool,
+    }
+
+    impl<B: I2c> OledRenderer<B> {
+        pub fn new(oled: Oled<B>) -> Self {
+            OledRenderer {
+                oled,
+                framebuffer: Framebuffer::new(),
+                flushed: None,
+                display_on: true,
+            }
+        }
+
+        // like the LCD display buffer, only send the pages that actually changed
+        async fn flush(&mut self) {
+            for page in 0..PAGES {
+                let changed = match &self.flushed {
+                    Some(flushed) => flushed.page(page) != self.framebuffer.page(page),
+                    None => true,
+                };
+                if changed {
+                    self.oled.write_page(page, self.framebuffer.page(page)).await;
+                }
+            }
+            self.flushed = Some(self.framebuffer.clone());
+        }
+    }
+
+    impl<B: I2c> TramDisplay for OledRenderer<B> {
+        async fn render<'b, const LINES: usize, const DIRECTIONS: usize, const PASSAGES: usize>(
+            &'b mut self,
+            state: &'b UiState<LINES, DIRECTIONS, PASSAGES>,
+        ) {
+            if self.display_on != state.backlight_on {
+                self.oled.set_display_on(state.backlight_on).await;
+                self.display_on = state.backlight_on;
+            }
+            render_state(&mut self.framebuffer, state);
+            self.flush().await;
+        }
+
+        async fn healthcheck(&mut self) {
+            let was_connected = self.oled.is_connected;
+            self.oled.check_connected().await;
+            if !was_connected && self.oled.is_connected {
+                // the screen may have been power cycled, its content is unknown
+                self.oled.init().await;
+                self.flushed = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_time::Instant;
+
+    use super::*;
+    use crate::canvas::{PASSAGES_Y, ROW_HEIGHT, fit_text, render_state};
+    use crate::display::{ArrivalKind, Severity, Staleness, StatusMessage, TramDirectionState, TramLineState, TramNextPassage, UiState, WallClock};
+    use crate::font;
+    use embedded_hal_async::i2c::{ErrorKind, ErrorType, I2c, Operation};
+
+    const FOOTER_Y: u16 = HEIGHT as u16 - font::GLYPH_HEIGHT as u16;
+
+    fn state_with_passages(passages: &[(&str, u8, ArrivalKind)]) -> UiState {
+        let mut next_passages = heapless::Vec::new();
+        for (destination, relative_arrival, arrival_kind) in passages {
+            let _ = next_passages.push(TramNextPassage {
+                destination: heapless::String::try_from(*destination).unwrap(),
+                relative_arrival: *relative_arrival,
+                arrival_at: None,
+                arrival_kind: *arrival_kind,
+            });
+        }
+        let staleness = Staleness::default();
+        let mut directions = heapless::Vec::new();
+        let _ = directions.push(TramDirectionState {
+            update_at: heapless::String::try_from("14:35:10").unwrap(),
+            direction_id: 1,
+            next_passages,
+            computed_at: Instant::from_secs(0),
+            staleness,
+            disruption: None,
+        });
+        let mut lines = heapless::Vec::new();
+        let _ = lines.push(TramLineState {
+            id: heapless::String::try_from("C").unwrap(),
+            line: heapless::String::try_from("Tram C").unwrap(),
+            directions,
+        });
+        let mut state = UiState::new(staleness, Instant::from_secs(0));
+        state.lines = lines;
+        state
+    }
+
+    // the columns of the framebuffer between x and x + width, for the 8 pixel high row starting at y
+    fn row_columns(framebuffer: &Framebuffer, x: u16, y: u16, width: u16) -> heapless::Vec<u8, WIDTH> {
+        (x..x + width)
+            .map(|x| (0..8).fold(0u8, |acc, bit| acc | ((framebuffer.pixel(x, y + bit) as u8) << bit)))
+            .collect()
+    }
+
+    #[test]
+    fn glyphs_are_proportional() {
+        assert_eq!(font::glyph('i'), &[0x44, 0x7d, 0x40]);
+        assert_eq!(font::glyph('m').len(), 5);
+        assert_eq!(font::text_width("im"), 3 + 1 + 5);
+        assert_eq!(font::glyph('é'), font::glyph('e'));
+        assert_eq!(font::glyph('œ'), font::glyph('?'));
+    }
+
+    #[test]
+    fn draws_text_at_any_position() {
+        let mut framebuffer = Framebuffer::new();
+        let end = framebuffer.draw_text(10, 3, "i");
+        assert_eq!(end, 13);
+        assert_eq!(row_columns(&framebuffer, 10, 3, 3), [0x44, 0x7d, 0x40]);
+        assert!(!framebuffer.pixel(9, 3 + 2));
+        // the glyph spans two pages
+        assert_ne!(framebuffer.page(0)[11], 0);
+        assert_ne!(framebuffer.page(1)[11], 0);
+    }
+
+    #[test]
+    fn renders_line_and_passages() {
+        let state = state_with_passages(&[
+            ("Plaine des sports", 3, ArrivalKind::Realtime),
+            ("Universite - Condillac", 8, ArrivalKind::Scheduled),
+        ]);
+        let mut framebuffer = Framebuffer::new();
+        render_state(&mut framebuffer, &state);
+
+        let mut expected = Framebuffer::new();
+        expected.draw_text(0, 0, "Tram C");
+        expected.draw_hline(9);
+        expected.draw_text(0, PASSAGES_Y, "Plaine des sports");
+        expected.draw_text_right(WIDTH as u16, PASSAGES_Y, "3 min");
+        // too long for the space left by the minutes, cut on the right
+        expected.draw_text(0, PASSAGES_Y + ROW_HEIGHT, fit_text("Universite - Condillac", WIDTH as u16 - font::text_width("*8 min") - 4));
+        expected.draw_text_right(WIDTH as u16, PASSAGES_Y + ROW_HEIGHT, "*8 min");
+        expected.draw_text_right(WIDTH as u16, FOOTER_Y, "14:35:10");
+
+        assert!(framebuffer == expected);
+        assert!(fit_text("Universite - Condillac", WIDTH as u16 - font::text_width("*8 min") - 4).len() < "Universite - Condillac".len());
+    }
+
+    #[test]
+    fn minutes_are_right_aligned() {
+        let state = state_with_passages(&[("Gare", 12, ArrivalKind::Realtime)]);
+        let mut framebuffer = Framebuffer::new();
+        render_state(&mut framebuffer, &state);
+
+        // last column of the `n` of "min" is the last column of the screen
+        assert_eq!(row_columns(&framebuffer, WIDTH as u16 - 1, PASSAGES_Y, 1)[0], *font::glyph('n').last().unwrap());
+    }
+
+    #[test]
+    fn flags_stale_data() {
+        let mut state = state_with_passages(&[("Gare", 12, ArrivalKind::Realtime)]);
+        state.now = Instant::from_secs(150);
+        let mut framebuffer = Framebuffer::new();
+        render_state(&mut framebuffer, &state);
+
+        let mut warning = Framebuffer::new();
+        warning.draw_text(0, FOOTER_Y, "ANCIENNES");
+        let width = font::text_width("ANCIENNES");
+        assert_eq!(row_columns(&framebuffer, 0, FOOTER_Y, width), row_columns(&warning, 0, FOOTER_Y, width));
+    }
+
+    #[test]
+    fn warnings_replace_the_footer() {
+        let mut state = state_with_passages(&[("Gare", 12, ArrivalKind::Realtime)]);
+        state.current_message = Some(StatusMessage {
+            text: heapless::String::try_from("Wifi: signal faible").unwrap(),
+            severity: Severity::Warning,
+            expires_at: None,
+        });
+        let mut framebuffer = Framebuffer::new();
+        render_state(&mut framebuffer, &state);
+
+        let mut expected = Framebuffer::new();
+        expected.draw_text(0, 0, "Tram C");
+        expected.draw_hline(9);
+        expected.draw_text(0, PASSAGES_Y, "Gare");
+        expected.draw_text_right(WIDTH as u16, PASSAGES_Y, "12 min");
+        expected.draw_hline(FOOTER_Y - 2);
+        expected.draw_text(0, FOOTER_Y, "Wifi: signal faible");
+        assert!(framebuffer == expected);
+    }
+
+    #[test]
+    fn night_mode_shows_the_clock() {
+        let mut state = state_with_passages(&[("Gare", 12, ArrivalKind::Realtime)]);
+        state.clock = Some(WallClock { unix_secs: 1_760_618_280, set_at: Instant::from_secs(0) });
+        state.night_mode = true;
+        let mut framebuffer = Framebuffer::new();
+        render_state(&mut framebuffer, &state);
+
+        let date = "jeu. 16 oct. 2025";
+        let (x, width) = ((WIDTH as u16 - font::text_width(date)) / 2, font::text_width(date));
+        let mut expected = Framebuffer::new();
+        expected.draw_text(x, FOOTER_Y, date);
+        assert_eq!(row_columns(&framebuffer, x, FOOTER_Y, width), row_columns(&expected, x, FOOTER_Y, width));
+        // no line name, the time is below it
+        assert!(row_columns(&framebuffer, 0, 0, WIDTH as u16).iter().all(|column| *column == 0));
+        assert!(row_columns(&framebuffer, 0, 24, WIDTH as u16).iter().any(|column| *column != 0));
+    }
+
+    #[test]
+    fn wraps_messages() {
+        let mut state = state_with_passages(&[]);
+        state.lines.clear();
+        state.current_message = Some(StatusMessage {
+            text: heapless::String::try_from("Waiting to get IP address... and a long message").unwrap(),
+            severity: Severity::Info,
+            expires_at: None,
+        });
+        let mut framebuffer = Framebuffer::new();
+        render_state(&mut framebuffer, &state);
+
+        let first_row = fit_text("Waiting to get IP address... and a long message", WIDTH as u16);
+        let mut expected = Framebuffer::new();
+        expected.draw_text(0, 0, first_row);
+        expected.draw_text(0, 10, &"Waiting to get IP address... and a long message"[first_row.len()..]);
+        assert!(framebuffer == expected);
+    }
+
+    // records every I2C write, one entry per transaction, in a log kept by the test while the renderer owns the bus
+    type Writes = std::rc::Rc<core::cell::RefCell<std::vec::Vec<std::vec::Vec<u8>>>>;
+    struct MockBus {
+        writes: Writes,
+    }
+
+    impl ErrorType for MockBus {
+        type Error = ErrorKind;
+    }
+
+    impl I2c for MockBus {
+        async fn transaction(&mut self, _address: u8, operations: &mut [Operation<'_>]) -> Result<(), Self::Error> {
+            for operation in operations {
+                if let Operation::Write(data) = operation {
+                    self.writes.borrow_mut().push(data.to_vec());
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn only_sends_the_changed_pages() {
+        use crate::display::TramDisplay;
+
+        let writes = Writes::default();
+        let mut renderer = OledRenderer::new(Oled::new(MockBus { writes: writes.clone() }, 0x3C, OledController::Sh1106));
+        let mut state = state_with_passages(&[("Gare", 3, ArrivalKind::Realtime)]);
+        embassy_futures::block_on(renderer.render(&state));
+        // every page at boot: the page and column commands then the data, the SH1106 starts at column 2
+        let sent = writes.take();
+        assert_eq!(sent.len(), 2 * PAGES);
+        assert_eq!(sent[0], [0x00, 0xB0, 0x02, 0x10]);
+        assert_eq!((sent[1][0], sent[1].len()), (0x40, WIDTH + 1));
+
+        embassy_futures::block_on(renderer.render(&state));
+        assert!(writes.borrow().is_empty());
+
+        // only the pages of the first passage, its row straddles two of them
+        state.lines[0].directions[0].next_passages[0].relative_arrival = 4;
+        embassy_futures::block_on(renderer.render(&state));
+        let sent = writes.take();
+        assert_eq!(sent.len(), 4);
+        assert_eq!((sent[0][1], sent[2][1]), (0xB1, 0xB2));
+    }
+}
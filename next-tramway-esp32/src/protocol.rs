@@ -0,0 +1,536 @@
+// This module turns raw MQTT messages (topic + payload) into UiCommands
+// It doesn't depend on the network stack or on esp-hal, so it can be tested on the host with `cargo test-host`
+
+use heapless::{String, Vec};
+
+use embassy_time::Duration;
+
+use crate::display::{ArrivalKind, LineId, TramNextPassage, UiCommand};
+use crate::{binary, json};
+
+pub const LINE_TOPIC_PREFIX: &str = "next-tramway/line/";
+pub const COMMAND_TOPIC_PREFIX: &str = "next-tramway/command/";
+// `next-tramway/line/<line>/<direction_id>/json` only accepts the JSON payload
+pub const JSON_TOPIC_SUFFIX: &str = "/json";
+// `next-tramway/line/<line>/<direction_id>/bin` carries the binary payload of the binary module
+pub const BINARY_TOPIC_SUFFIX: &str = "/bin";
+
+// field of the payload that didn't fit in its fixed capacity buffer
+// the line id is the key of the line, a payload with one too long is rejected, the other fields keep what fits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    LineId,
+    LineName,
+    Destination,
+    Passages,
+    UpdateAt,
+    Disruption,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    UnknownTopic, // topic is neither a line topic nor a command topic
+    InvalidTopic, // line topic without the `<line>/<direction_id>` part
+    MissingLineName, // empty payload or empty first line
+    MissingUpdateAt, // payload without the timestamp line
+    MalformedPassage, // passage line without the `destination|minutes|R or S` fields
+    InvalidMinutes,
+    InvalidArrivalKind, // neither `R` nor `S`
+    InvalidArrivalTime, // the optional 4th field of a passage isn't a unix time
+    InvalidDirectionId,
+    Overflow(Field), // the line id doesn't fit in the UiState buffers, the other fields are truncated instead
+    UnknownCommand,
+    InvalidDuration, // not a number of seconds, or 0
+    InvalidJson, // JSON syntax error, missing field or value of the wrong type
+    NotUtf8, // binary payload on a topic expecting text
+    InvalidBinary, // truncated binary payload, or with more passages than the device keeps
+    UnsupportedVersion(u8), // binary payload of a schema version this firmware doesn't know
+}
+
+// command to apply, with the first field of the payload that had to be truncated to fit in it, if any
+// the command is applied all the same, the field is only reported (log, diagnostics, MQTT warning)
+pub type Parsed<const PASSAGES: usize> = (UiCommand<PASSAGES>, Option<Field>);
+
+// Entry point used by the MQTT task, the payload is only decoded as UTF-8 for the text formats
+pub fn parse_payload<const PASSAGES: usize>(topic: &str, payload: &[u8]) -> Result<Parsed<PASSAGES>, ParseError> {
+    let binary_topic = topic.strip_prefix(LINE_TOPIC_PREFIX).and_then(|rest| rest.strip_suffix(BINARY_TOPIC_SUFFIX));
+    if let Some(topic) = binary_topic {
+        if payload.is_empty() {
+            return Ok((parse_removal(topic)?, None));
+        }
+        let (line_id, direction_id) = parse_line_ids(topic)?;
+        // the binary fields have the capacities of the device, a payload that doesn't fit can't be decoded at all
+        return Ok((binary::decode(payload)?.into_command(line_id, direction_id), None));
+    }
+    let payload = core::str::from_utf8(payload).map_err(|_| ParseError::NotUtf8)?;
+    parse_message(topic, payload)
+}
+
+// dispatch the message depending on the topic
+pub fn parse_message<const PASSAGES: usize>(topic: &str, payload: &str) -> Result<Parsed<PASSAGES>, ParseError> {
+    if let Some(rest) = topic.strip_prefix(LINE_TOPIC_PREFIX) {
+        parse_line_update(rest, payload)
+    } else if let Some(command) = topic.strip_prefix(COMMAND_TOPIC_PREFIX) {
+        Ok((parse_command(command, payload)?, None))
+    } else {
+        Err(ParseError::UnknownTopic)
+    }
+}
+
+// `topic` is the part after `next-tramway/line/`, i.e. `<line>/<direction_id>`, optionally followed by `/json`
+// the payload is either JSON (see the json module) or the line oriented text format (see README):
+// Line Name
+// Destination1|Minutes1|R or S[|arrival unix time]
+// Destination2|Minutes2|R or S[|arrival unix time]
+// HH:MM:SS
+pub fn parse_line_update<const PASSAGES: usize>(topic: &str, payload: &str) -> Result<Parsed<PASSAGES>, ParseError> {
+    // without the suffix, a JSON payload is recognized by its opening brace, a line name never starts with one
+    let (topic, json) = match topic.strip_suffix(JSON_TOPIC_SUFFIX) {
+        Some(topic) => (topic, true),
+        None => (topic, json::looks_like_json(payload)),
+    };
+
+    // an empty payload deletes the retained message of the topic, the screen goes with it
+    if payload.is_empty() {
+        return Ok((parse_removal(topic)?, None));
+    }
+
+    let (line_id, direction_id) = parse_line_ids(topic)?;
+    if json {
+        json::parse_direction(line_id, direction_id, payload)
+    } else {
+        parse_text_direction(line_id, direction_id, payload)
+    }
+}
+
+fn parse_text_direction<const PASSAGES: usize>(line_id: LineId, direction_id: usize, payload: &str) -> Result<Parsed<PASSAGES>, ParseError> {
+    let mut overflow = None;
+    let mut payload_lines = payload.split('\n');
+    let line_name = match payload_lines.next() {
+        Some(name) if !name.is_empty() => name,
+        _ => return Err(ParseError::MissingLineName),
+    };
+    let line = fit(line_name, Field::LineName, &mut overflow);
+    let update_at = payload_lines.next_back().ok_or(ParseError::MissingUpdateAt)?;
+    let update_at = fit(update_at, Field::UpdateAt, &mut overflow);
+
+    // the passages past the capacity are still parsed, so a malformed one is reported first, then dropped
+    let mut next_passages: Vec<TramNextPassage, PASSAGES> = Vec::new();
+    for passage in payload_lines {
+        if next_passages.push(parse_passage(passage, &mut overflow)?).is_err() {
+            overflow.get_or_insert(Field::Passages);
+        }
+    }
+
+    let command = UiCommand::UpdateDirection {
+        line_id,
+        line,
+        direction_id,
+        next_passages,
+        update_at,
+        disruption: None,
+        staleness: None,
+    };
+    Ok((command, overflow))
+}
+
+// the longest start of `text` that fits in the buffer, cut at a char boundary, and whether it had to be cut
+pub(crate) fn truncate<const N: usize>(text: &str) -> (String<N>, bool) {
+    let mut end = text.len().min(N);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    (String::try_from(&text[..end]).unwrap_or_default(), end < text.len())
+}
+
+// `text` truncated to fit, the first field truncated this way is kept in `overflow`
+fn fit<const N: usize>(text: &str, field: Field, overflow: &mut Option<Field>) -> String<N> {
+    let (fitted, truncated) = truncate(text);
+    if truncated {
+        overflow.get_or_insert(field);
+    }
+    fitted
+}
+
+// `<line>/<direction_id>` removes a direction, `<line>` alone removes the whole line
+fn parse_removal<const PASSAGES: usize>(topic: &str) -> Result<UiCommand<PASSAGES>, ParseError> {
+    let Some((line_id, direction_id)) = parse_line_and_direction(topic) else {
+        if topic.is_empty() {
+            return Err(ParseError::InvalidTopic);
+        }
+        let line_id = String::try_from(topic).map_err(|_| ParseError::Overflow(Field::LineId))?;
+        return Ok(UiCommand::RemoveLine { line_id });
+    };
+    Ok(UiCommand::RemoveDirection {
+        line_id: String::try_from(line_id).map_err(|_| ParseError::Overflow(Field::LineId))?,
+        direction_id: direction_id.parse().map_err(|_| ParseError::InvalidDirectionId)?,
+    })
+}
+
+// `<line>/<direction_id>` of a line topic, whatever the payload format
+fn parse_line_ids(topic: &str) -> Result<(LineId, usize), ParseError> {
+    let (line_id, direction_id) = parse_line_and_direction(topic).ok_or(ParseError::InvalidTopic)?;
+    let line_id = String::try_from(line_id).map_err(|_| ParseError::Overflow(Field::LineId))?;
+    let direction_id = direction_id.parse().map_err(|_| ParseError::InvalidDirectionId)?;
+    Ok((line_id, direction_id))
+}
+
+// `<line>/<direction_id>`, shared by the line topics and the screen command
+fn parse_line_and_direction(s: &str) -> Option<(&str, &str)> {
+    let mut parts = s.rsplit('/');
+    let (Some(direction_id), Some(line_id)) = (parts.next(), parts.next()) else {
+        return None;
+    };
+    Some((line_id, direction_id))
+}
+
+fn parse_passage(passage: &str, overflow: &mut Option<Field>) -> Result<TramNextPassage, ParseError> {
+    let mut passage_parts = passage.split('|');
+    let (Some(destination), Some(relative_arrival), Some(arrival_kind)) = (
+        passage_parts.next(),
+        passage_parts.next(),
+        passage_parts.next(),
+    ) else {
+        return Err(ParseError::MalformedPassage);
+    };
+
+    Ok(TramNextPassage {
+        destination: fit(destination, Field::Destination, overflow),
+        relative_arrival: relative_arrival.parse().map_err(|_| ParseError::InvalidMinutes)?,
+        arrival_at: passage_parts.next().map(|at| at.parse().map_err(|_| ParseError::InvalidArrivalTime)).transpose()?,
+        arrival_kind: match arrival_kind {
+            "R" => ArrivalKind::Realtime,
+            "S" => ArrivalKind::Scheduled,
+            _ => return Err(ParseError::InvalidArrivalKind),
+        },
+    })
+}
+
+// `command` is the part after `next-tramway/command/`
+// - backlight: `on` / `off`
+// - rotation: `on` / `off`, or the number of seconds each screen stays
+// - screen: `next`, `prev`, `line/<line>/<direction_id>`, `clock`, `pin`, `unpin`
+// - night: `on` / `off`, only the clock is shown while it's on
+// - any command: `toggle_backlight`
+pub fn parse_command<const PASSAGES: usize>(command: &str, payload: &str) -> Result<UiCommand<PASSAGES>, ParseError> {
+    match (command, payload) {
+        ("backlight", "on") => Ok(UiCommand::SetBacklight(true)),
+        ("backlight", "off") => Ok(UiCommand::SetBacklight(false)),
+        ("rotation", "on") => Ok(UiCommand::SetAutoRotation(true)),
+        ("rotation", "off") => Ok(UiCommand::SetAutoRotation(false)),
+        ("rotation", secs) if secs.starts_with(|c: char| c.is_ascii_digit()) => match secs.parse() {
+            Ok(secs) if secs > 0 => Ok(UiCommand::SetRotationDwell(Duration::from_secs(secs))),
+            _ => Err(ParseError::InvalidDuration),
+        },
+        ("screen", "next") => Ok(UiCommand::NextScreen),
+        ("screen", "prev") => Ok(UiCommand::PreviousScreen),
+        ("screen", "pin") => Ok(UiCommand::SetPinned(true)),
+        ("screen", "unpin") => Ok(UiCommand::SetPinned(false)),
+        ("screen", "clock") => Ok(UiCommand::ShowClock),
+        ("night", "on") => Ok(UiCommand::SetNightMode(true)),
+        ("night", "off") => Ok(UiCommand::SetNightMode(false)),
+        ("screen", screen) if screen.starts_with("line/") => {
+            let (line_id, direction_id) =
+                parse_line_and_direction(&screen["line/".len()..]).ok_or(ParseError::UnknownCommand)?;
+            Ok(UiCommand::ShowDirection {
+                line_id: String::try_from(line_id).map_err(|_| ParseError::Overflow(Field::LineId))?,
+                direction_id: direction_id.parse().map_err(|_| ParseError::InvalidDirectionId)?,
+            })
+        }
+        (_, "toggle_backlight") => Ok(UiCommand::ToggleBacklight),
+        _ => Err(ParseError::UnknownCommand),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // with the default capacities, so the commands don't need a type annotation
+    // for the payloads that fit, see `parse_truncated` for the others
+    fn parse_message(topic: &str, payload: &str) -> Result<UiCommand, ParseError> {
+        super::parse_message(topic, payload).map(|(command, overflow)| {
+            assert_eq!(overflow, None, "{payload}");
+            command
+        })
+    }
+
+    fn parse_truncated(payload: &str) -> (String<16>, Vec<TramNextPassage, 3>, String<10>, Option<Field>) {
+        match super::parse_message("next-tramway/line/C/1", payload) {
+            Ok((UiCommand::UpdateDirection { line, next_passages, update_at, .. }, overflow)) => (line, next_passages, update_at, overflow),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    fn parse_update(topic: &str, payload: &str) -> (String<16>, usize, Vec<TramNextPassage, 3>, String<10>) {
+        match parse_message(topic, payload) {
+            Ok(UiCommand::UpdateDirection { line, direction_id, next_passages, update_at, .. }) => {
+                (line, direction_id, next_passages, update_at)
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_readme_example() {
+        let (line, direction_id, passages, update_at) = parse_update(
+            "next-tramway/line/C/1",
+            "Tram C\nPlaine des sports|3|R\nUniversite - Condillac|8|S\n14:35:10",
+        );
+        assert_eq!(line, "Tram C");
+        assert_eq!(direction_id, 1);
+        assert_eq!(update_at, "14:35:10");
+        assert_eq!(passages.len(), 2);
+        assert_eq!(passages[0].destination, "Plaine des sports");
+        assert_eq!(passages[0].relative_arrival, 3);
+        assert_eq!(passages[0].arrival_kind, ArrivalKind::Realtime);
+        assert_eq!(passages[1].destination, "Universite - Condillac");
+        assert_eq!(passages[1].relative_arrival, 8);
+        assert_eq!(passages[1].arrival_kind, ArrivalKind::Scheduled);
+    }
+
+    #[test]
+    fn passages_can_carry_their_arrival_time() {
+        let (_, _, passages, _) = parse_update(
+            "next-tramway/line/C/1",
+            "Tram C\nPlaine des sports|3|R|1760625480\nUniversite - Condillac|8|S\n14:35:10",
+        );
+        assert_eq!(passages[0].relative_arrival, 3);
+        assert_eq!(passages[0].arrival_at, Some(1_760_625_480));
+        assert_eq!(passages[1].arrival_at, None);
+        assert_eq!(
+            parse_message("next-tramway/line/C/1", "Tram C\nGare|3|R|14:38\n14:35:10").unwrap_err(),
+            ParseError::InvalidArrivalTime
+        );
+    }
+
+    #[test]
+    fn parses_line_without_passages() {
+        let (line, direction_id, passages, update_at) = parse_update("next-tramway/line/A/2", "Tram A\n14:35:10");
+        assert_eq!(line, "Tram A");
+        assert_eq!(direction_id, 2);
+        assert!(passages.is_empty());
+        assert_eq!(update_at, "14:35:10");
+    }
+
+    #[test]
+    fn rejects_bad_fields() {
+        assert_eq!(
+            parse_message("next-tramway/line/C/1", "\n14:35:10").unwrap_err(),
+            ParseError::MissingLineName
+        );
+        assert_eq!(
+            parse_message("next-tramway/line/C/1", "Tram C").unwrap_err(),
+            ParseError::MissingUpdateAt
+        );
+        assert_eq!(
+            parse_message("next-tramway/line/C/1", "Tram C\nGare|soon|R\n14:35:10").unwrap_err(),
+            ParseError::InvalidMinutes
+        );
+        assert_eq!(
+            parse_message("next-tramway/line/C/1", "Tram C\nGare|3\n14:35:10").unwrap_err(),
+            ParseError::MalformedPassage
+        );
+        assert_eq!(
+            parse_message("next-tramway/line/C/1", "Tram C\nGare|3|X\n14:35:10").unwrap_err(),
+            ParseError::InvalidArrivalKind
+        );
+        assert_eq!(
+            parse_message("next-tramway/line/C/north", "Tram C\n14:35:10").unwrap_err(),
+            ParseError::InvalidDirectionId
+        );
+        assert_eq!(
+            parse_message("next-tramway/line/1", "Tram C\n14:35:10").unwrap_err(),
+            ParseError::InvalidTopic
+        );
+        assert_eq!(
+            parse_message("somewhere/else", "Tram C\n14:35:10").unwrap_err(),
+            ParseError::UnknownTopic
+        );
+    }
+
+    #[test]
+    fn truncates_overflowing_fields() {
+        let (line, _, _, overflow) = parse_truncated("A very long line name\n14:35:10");
+        assert_eq!((line.as_str(), overflow), ("A very long line", Some(Field::LineName)));
+
+        // the first passages are kept
+        let (_, passages, update_at, overflow) = parse_truncated("Tram C\nA|1|R\nB|2|R\nC|3|R\nD|4|R\n14:35:10");
+        let destinations: std::vec::Vec<_> = passages.iter().map(|p| p.destination.as_str()).collect();
+        assert_eq!(destinations, ["A", "B", "C"]);
+        assert_eq!((update_at.as_str(), overflow), ("14:35:10", Some(Field::Passages)));
+        // but they are all checked
+        assert_eq!(
+            parse_message("next-tramway/line/C/1", "Tram C\nA|1|R\nB|2|R\nC|3|R\nD|soon|R\n14:35:10").unwrap_err(),
+            ParseError::InvalidMinutes
+        );
+
+        let (_, _, update_at, overflow) = parse_truncated("Tram C\n2024-01-01 14:35:10");
+        assert_eq!((update_at.as_str(), overflow), ("2024-01-01", Some(Field::UpdateAt)));
+
+        // cut before an accented letter rather than in the middle of it
+        let (_, passages, _, overflow) = parse_truncated("Tram C\nUniversite - Condillac - Saint-é|3|R\n14:35:10");
+        assert_eq!((passages[0].destination.as_str(), overflow), ("Universite - Condillac - Saint-", Some(Field::Destination)));
+        // only the first truncated field is reported
+        let (_, passages, _, overflow) = parse_truncated("Tram C (Gieres - Sassenage)\nUniversite - Condillac - Saint-Martin|3|R\n14:35:10");
+        assert_eq!((passages[0].destination.as_str(), overflow), ("Universite - Condillac - Saint-M", Some(Field::LineName)));
+
+        // the line id is the key of the line, it can't be truncated
+        assert_eq!(
+            parse_message("next-tramway/line/A-very-long-line-id/1", "Tram C\n14:35:10").unwrap_err(),
+            ParseError::Overflow(Field::LineId)
+        );
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert!(matches!(
+            parse_message("next-tramway/command/backlight", "on"),
+            Ok(UiCommand::SetBacklight(true))
+        ));
+        assert!(matches!(
+            parse_message("next-tramway/command/backlight", "off"),
+            Ok(UiCommand::SetBacklight(false))
+        ));
+        assert!(matches!(
+            parse_message("next-tramway/command/misc", "toggle_backlight"),
+            Ok(UiCommand::ToggleBacklight)
+        ));
+        assert_eq!(
+            parse_message("next-tramway/command/backlight", "dim").unwrap_err(),
+            ParseError::UnknownCommand
+        );
+    }
+
+    #[test]
+    fn keeps_the_line_id_of_the_topic() {
+        assert!(matches!(
+            parse_message("next-tramway/line/C11/2", "Bus C11\n14:35:10"),
+            Ok(UiCommand::UpdateDirection { line_id, direction_id: 2, .. }) if line_id == "C11"
+        ));
+    }
+
+    #[test]
+    fn parses_screen_commands() {
+        assert!(matches!(parse_message("next-tramway/command/screen", "next"), Ok(UiCommand::NextScreen)));
+        assert!(matches!(parse_message("next-tramway/command/screen", "prev"), Ok(UiCommand::PreviousScreen)));
+        assert!(matches!(parse_message("next-tramway/command/screen", "pin"), Ok(UiCommand::SetPinned(true))));
+        assert!(matches!(parse_message("next-tramway/command/screen", "unpin"), Ok(UiCommand::SetPinned(false))));
+        assert!(matches!(parse_message("next-tramway/command/screen", "clock"), Ok(UiCommand::ShowClock)));
+        assert!(matches!(parse_message("next-tramway/command/night", "on"), Ok(UiCommand::SetNightMode(true))));
+        assert!(matches!(parse_message("next-tramway/command/night", "off"), Ok(UiCommand::SetNightMode(false))));
+        assert!(matches!(
+            parse_message("next-tramway/command/screen", "line/C/1"),
+            Ok(UiCommand::ShowDirection { line_id, direction_id: 1 }) if line_id == "C"
+        ));
+        assert_eq!(
+            parse_message("next-tramway/command/screen", "line/C").unwrap_err(),
+            ParseError::UnknownCommand
+        );
+        assert_eq!(
+            parse_message("next-tramway/command/screen", "line/C/north").unwrap_err(),
+            ParseError::InvalidDirectionId
+        );
+        assert_eq!(
+            parse_message("next-tramway/command/screen", "last").unwrap_err(),
+            ParseError::UnknownCommand
+        );
+    }
+
+    #[test]
+    fn parses_rotation_commands() {
+        assert!(matches!(
+            parse_message("next-tramway/command/rotation", "on"),
+            Ok(UiCommand::SetAutoRotation(true))
+        ));
+        assert!(matches!(
+            parse_message("next-tramway/command/rotation", "off"),
+            Ok(UiCommand::SetAutoRotation(false))
+        ));
+        assert!(matches!(
+            parse_message("next-tramway/command/rotation", "15"),
+            Ok(UiCommand::SetRotationDwell(dwell)) if dwell == Duration::from_secs(15)
+        ));
+        assert_eq!(
+            parse_message("next-tramway/command/rotation", "0").unwrap_err(),
+            ParseError::InvalidDuration
+        );
+        assert_eq!(
+            parse_message("next-tramway/command/rotation", "15s").unwrap_err(),
+            ParseError::InvalidDuration
+        );
+        assert_eq!(
+            parse_message("next-tramway/command/rotation", "fast").unwrap_err(),
+            ParseError::UnknownCommand
+        );
+    }
+
+    #[test]
+    fn empty_payload_removes_the_screen() {
+        match parse_message("next-tramway/line/C/2", "").unwrap() {
+            UiCommand::RemoveDirection { line_id, direction_id } => {
+                assert_eq!(line_id, "C");
+                assert_eq!(direction_id, 2);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match parse_message("next-tramway/line/C", "").unwrap() {
+            UiCommand::RemoveLine { line_id } => assert_eq!(line_id, "C"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(parse_message("next-tramway/line/", "").unwrap_err(), ParseError::InvalidTopic);
+        assert_eq!(parse_message("next-tramway/line/C/north", "").unwrap_err(), ParseError::InvalidDirectionId);
+    }
+
+    #[test]
+    fn json_payloads_by_suffix_or_content() {
+        let payload = r#"{"line": "Tram C", "passages": [{"destination": "Gare", "minutes": 3, "realtime": true}], "update_at": "14:35:10"}"#;
+        for topic in ["next-tramway/line/C/1", "next-tramway/line/C/1/json"] {
+            let (line, direction_id, passages, update_at) = parse_update(topic, payload);
+            assert_eq!((line.as_str(), direction_id, update_at.as_str()), ("Tram C", 1, "14:35:10"));
+            assert_eq!(passages[0].destination, "Gare");
+        }
+        // the suffix doesn't let a text payload through, and the empty payload still removes the screen
+        assert_eq!(parse_message("next-tramway/line/C/1/json", "Tram C\nGare|3|R\n14:35:10").unwrap_err(), ParseError::InvalidJson);
+        assert!(matches!(parse_message("next-tramway/line/C/1/json", ""), Ok(UiCommand::RemoveDirection { direction_id: 1, .. })));
+        // the text format is still recognized next to it
+        assert_eq!(parse_update("next-tramway/line/C/1", "Tram C\nGare|3|R\n14:35:10").2[0].destination, "Gare");
+    }
+
+    #[test]
+    fn binary_payloads_on_the_bin_topics() {
+        let mut passages = Vec::new();
+        let _ = passages.push(binary::Passage { destination: String::try_from("Gare").unwrap(), minutes: 3, realtime: true, at: None });
+        let update: binary::DirectionUpdate = binary::DirectionUpdate {
+            line: String::try_from("Tram C").unwrap(),
+            passages,
+            update_at: String::try_from("14:35:10").unwrap(),
+            disruption: None,
+        };
+        let mut buffer = [0; 64];
+        let payload = binary::encode(&update, &mut buffer).unwrap();
+
+        match parse_payload::<3>("next-tramway/line/C/2/bin", payload) {
+            Ok((UiCommand::UpdateDirection { line_id, direction_id: 2, next_passages, .. }, None)) => {
+                assert_eq!(line_id, "C");
+                assert_eq!(next_passages[0].destination, "Gare");
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(matches!(parse_payload::<3>("next-tramway/line/C/2/bin", b""), Ok((UiCommand::RemoveDirection { direction_id: 2, .. }, None))));
+        // elsewhere, the binary payload isn't text
+        assert_eq!(parse_payload::<3>("next-tramway/line/C/2", &[binary::SCHEMA_VERSION, 0xFF]).unwrap_err(), ParseError::NotUtf8);
+        assert!(parse_payload::<3>("next-tramway/line/C/2", b"Tram C\nGare|3|R\n14:35:10").is_ok());
+    }
+
+    #[test]
+    fn passages_fit_the_chosen_capacity() {
+        let payload = "Tram C\nGare|1|R\nGare|5|R\nGare|9|R\nGare|13|S\nGare|17|S\n14:35:10";
+        let (_, passages, _, overflow) = parse_truncated(payload);
+        assert_eq!((passages.len(), overflow), (3, Some(Field::Passages)));
+        match super::parse_message::<5>("next-tramway/line/C/1", payload) {
+            Ok((UiCommand::UpdateDirection { next_passages, .. }, None)) => assert_eq!(next_passages.len(), 5),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,549 @@
+// Host side simulation of the UI, to iterate on layouts without hardware
+// - `SimulatedLcd` is a TramDisplay that keeps the character grid the LCD would show (same layout code as `LcdRenderer`)
+// - `Scenario` reads a script of MQTT messages, button presses and waits, see `scenarios/` for examples
+// - `Simulator` plays the scenario steps through `apply_ui_command`, like the renderer task does on the device
+// The `simulator` binary prints the screens in a terminal, the tests below use them as snapshots
+
+use core::fmt;
+
+use embassy_time::{Duration, Instant};
+
+use crate::display::{Severity, Staleness, TramDisplay, UiCommand, UiState, apply_ui_command};
+use crate::lcd::{CharMap, LcdGeometry, LcdRom, LcdScreen, render_screen};
+use crate::protocol::{self, ParseError};
+
+pub struct SimulatedLcd {
+    geometry: LcdGeometry,
+    screen: LcdScreen,
+    backlight_on: bool,
+    charmap: CharMap, // the accented letters without a glyph are shown like on the LCD
+}
+
+impl SimulatedLcd {
+    pub fn new(geometry: LcdGeometry, rom: LcdRom) -> Self {
+        SimulatedLcd { geometry, screen: LcdScreen::default(), backlight_on: true, charmap: CharMap::new(rom) }
+    }
+
+    pub fn screen(&self) -> &LcdScreen {
+        &self.screen
+    }
+}
+
+impl TramDisplay for SimulatedLcd {
+    async fn render<'a, const LINES: usize, const DIRECTIONS: usize, const PASSAGES: usize>(
+        &'a mut self,
+        state: &'a UiState<LINES, DIRECTIONS, PASSAGES>,
+    ) {
+        self.backlight_on = state.backlight_on;
+        if let Some(screen) = render_screen(state, self.geometry) {
+            self.charmap.allocate(&screen[..self.geometry.rows()]);
+            self.screen = screen;
+        }
+    }
+
+    async fn healthcheck(&mut self) {}
+}
+
+// the character grid with a frame around it, the frame is drawn with `.` when the backlight is off
+impl fmt::Display for SimulatedLcd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (corner, horizontal, vertical) = if self.backlight_on { ('+', '-', '|') } else { ('.', '.', ':') };
+        let columns = self.geometry.columns();
+
+        write!(f, "{}", corner)?;
+        for _ in 0..columns {
+            write!(f, "{}", horizontal)?;
+        }
+        writeln!(f, "{}", corner)?;
+        for row in self.screen.iter().take(self.geometry.rows()) {
+            write!(f, "{}", vertical)?;
+            let mut printed = 0;
+            for c in row.chars().take(columns) {
+                write!(f, "{}", self.charmap.preview(c))?;
+                printed += 1;
+            }
+            for _ in printed..columns {
+                write!(f, " ")?;
+            }
+            writeln!(f, "{}", vertical)?;
+        }
+        write!(f, "{}", corner)?;
+        for _ in 0..columns {
+            write!(f, "{}", horizontal)?;
+        }
+        writeln!(f, "{}", corner)
+    }
+}
+
+// UiCommand is big anyway, boxing it would need an allocator
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum Step {
+    Command(UiCommand),
+    Wait(Duration), // move the simulated clock forward, followed by a render tick
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScenarioErrorKind {
+    UnknownStep,
+    MissingArgument,
+    InvalidNumber,
+    UnterminatedPayload, // `publish` without the `.` line ending the payload
+    PayloadOverflow,
+    Parse(ParseError), // the payload is rejected by the protocol module, like it would be on the device
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScenarioError {
+    pub line: usize, // 1-based, for the error messages
+    pub kind: ScenarioErrorKind,
+}
+
+// Script format, one step per line, empty lines and lines starting with `#` are ignored:
+// publish <topic>      MQTT message, followed by the payload lines and a line with a single `.`
+// next                 button press
+// prev                 previous screen (remote command)
+// message <text>       info message, like the ones sent during the boot
+// warning <text>       warning message, on the last row when there are passages to show
+// error <text>         error message, over the passages (e.g. connection lost)
+// wait <seconds>       let the time pass (countdown, staleness)
+// time <unix seconds>  set the clock of the device, like a time source would (absolute arrivals)
+pub struct Scenario<'a> {
+    lines: core::iter::Enumerate<core::str::Lines<'a>>,
+}
+
+impl<'a> Scenario<'a> {
+    pub fn new(script: &'a str) -> Self {
+        Scenario { lines: script.lines().enumerate() }
+    }
+
+    fn publish(&mut self, topic: &str) -> Result<Step, ScenarioErrorKind> {
+        let mut payload: heapless::String<512> = heapless::String::new();
+        loop {
+            let Some((_, line)) = self.lines.next() else {
+                return Err(ScenarioErrorKind::UnterminatedPayload);
+            };
+            if line == "." {
+                break;
+            }
+            if !payload.is_empty() {
+                payload.push('\n').map_err(|_| ScenarioErrorKind::PayloadOverflow)?;
+            }
+            payload.push_str(line).map_err(|_| ScenarioErrorKind::PayloadOverflow)?;
+        }
+        // like on the device, what fits is shown
+        protocol::parse_message(topic, &payload)
+            .map(|(command, _)| Step::Command(command))
+            .map_err(ScenarioErrorKind::Parse)
+    }
+}
+
+impl Iterator for Scenario<'_> {
+    type Item = Result<Step, ScenarioError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (index, line) = self.lines.next()?;
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (keyword, argument) = line.split_once(' ').unwrap_or((line, ""));
+            let step = match keyword {
+                "publish" if argument.is_empty() => Err(ScenarioErrorKind::MissingArgument),
+                "publish" => self.publish(argument),
+                "next" => Ok(Step::Command(UiCommand::NextScreen)),
+                "prev" => Ok(Step::Command(UiCommand::PreviousScreen)),
+                "message" => status_message(Severity::Info, argument),
+                "warning" => status_message(Severity::Warning, argument),
+                "error" => status_message(Severity::Error, argument),
+                "wait" => argument
+                    .parse()
+                    .map(|secs| Step::Wait(Duration::from_secs(secs)))
+                    .map_err(|_| ScenarioErrorKind::InvalidNumber),
+                "time" => argument
+                    .parse()
+                    .map(|unix_secs| Step::Command(UiCommand::SetTime { unix_secs }))
+                    .map_err(|_| ScenarioErrorKind::InvalidNumber),
+                _ => Err(ScenarioErrorKind::UnknownStep),
+            };
+            return Some(step.map_err(|kind| ScenarioError { line: index + 1, kind }));
+        }
+    }
+}
+
+fn status_message(severity: Severity, text: &str) -> Result<Step, ScenarioErrorKind> {
+    let text = heapless::String::try_from(text).map_err(|_| ScenarioErrorKind::PayloadOverflow)?;
+    Ok(Step::Command(UiCommand::UpdateMessage { text, severity, expires_after: None }))
+}
+
+pub struct Simulator {
+    pub state: UiState,
+    pub display: SimulatedLcd,
+}
+
+impl Simulator {
+    // the simulated clock starts at 0
+    pub fn new(geometry: LcdGeometry, rom: LcdRom, staleness: Staleness) -> Self {
+        Simulator {
+            state: UiState::new(staleness, Instant::from_secs(0)),
+            display: SimulatedLcd::new(geometry, rom),
+        }
+    }
+
+    // same as one iteration of the renderer task
+    pub fn apply(&mut self, step: Step) {
+        let (cmd, now) = match step {
+            Step::Command(cmd) => (cmd, self.state.now),
+            Step::Wait(duration) => (UiCommand::Tick, self.state.now + duration),
+        };
+        apply_ui_command(&mut self.state, cmd, now);
+        embassy_futures::block_on(self.display.render(&self.state));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // play the whole scenario and return the last screen
+    fn play(script: &str) -> std::string::String {
+        play_on(LcdGeometry::L2004, script)
+    }
+
+    fn play_on(geometry: LcdGeometry, script: &str) -> std::string::String {
+        let mut simulator = Simulator::new(geometry, LcdRom::A00, Staleness::default());
+        for step in Scenario::new(script) {
+            simulator.apply(step.unwrap());
+        }
+        std::format!("{}", simulator.display)
+    }
+
+    #[test]
+    fn readme_example() {
+        let screen = play(
+            "publish next-tramway/line/C/1
+Tram C
+Plaine des sports|3|R
+Universite - Condillac|8|S
+14:35:10
+.
+",
+        );
+        assert_eq!(
+            screen,
+            "+--------------------+
+|Tram C              |
+|Plaine des sports  3|
+|Universite - Cond* 8|
+|            14:35:10|
++--------------------+
+"
+        );
+    }
+
+    #[test]
+    fn shows_what_fits_of_a_payload_too_big() {
+        let screen = play(
+            "publish next-tramway/line/C/1
+Tram C (Gieres - Sassenage)
+Plaine des sports|3|R
+Universite - Condillac|8|S
+Gieres - Plaine des sports|12|S
+Gare|15|S
+14:35:10
+.
+",
+        );
+        assert_eq!(
+            screen,
+            "+--------------------+
+|Tram C (Gieres -    |
+|Plaine des sports  3|
+|Universite - Cond* 8|
+|            14:35:10|
++--------------------+
+"
+        );
+    }
+
+    #[test]
+    fn empty_line() {
+        let screen = play(
+            "publish next-tramway/line/A/1
+Tram A
+14:35:10
+.
+",
+        );
+        assert_eq!(
+            screen,
+            "+--------------------+
+|Tram A              |
+|Pas de passage dans |
+|l'heure...          |
+|            14:35:10|
++--------------------+
+"
+        );
+    }
+
+    #[test]
+    fn countdown_and_staleness() {
+        let screen = play(
+            "publish next-tramway/line/C/1
+Tram C
+Gare|0|R
+Plaine des sports|3|R
+14:35:10
+.
+wait 150
+",
+        );
+        assert_eq!(
+            screen,
+            "+--------------------+
+|Tram C              |
+|Plaine des sports  1|
+|                    |
+|ANCIENNES   14:35:10|
++--------------------+
+"
+        );
+    }
+
+    #[test]
+    fn errors_hide_the_passages_and_warnings_the_timestamp() {
+        let script = "message Connected to MQTT server !
+publish next-tramway/line/C/1
+Tram C
+Plaine des sports|3|R
+14:35:10
+.
+warning Wifi: signal faible
+";
+        assert_eq!(
+            play(script),
+            "+--------------------+
+|Tram C              |
+|Plaine des sports  3|
+|                    |
+|Wifi: signal faible |
++--------------------+
+"
+        );
+        assert_eq!(
+            play(&std::format!("{script}error MQTT connection lost\n")),
+            "+--------------------+
+|MQTT connection lost|
+|                    |
+|                    |
+|                    |
++--------------------+
+"
+        );
+    }
+
+    #[test]
+    fn json_payload_with_a_disruption() {
+        let script = r#"publish next-tramway/line/C/1/json
+{"line": "Tram C", "passages": [{"destination": "Plaine des sports", "minutes": 3, "realtime": true}],
+ "update_at": "14:35:10", "disruption": "Gares non desservi"}
+.
+"#;
+        assert_eq!(
+            play(script),
+            "+--------------------+
+|Tram C              |
+|Plaine des sports  3|
+|                    |
+|Gares non desservi  |
++--------------------+
+"
+        );
+        // the update time is back with the staleness warning
+        assert!(play(&std::format!("{script}wait 150\n")).contains("|ANCIENNES   14:35:10|"));
+    }
+
+    #[test]
+    fn absolute_arrivals_once_the_clock_is_set() {
+        // retained message published 5 minutes before it's received
+        let script = "publish next-tramway/line/C/1
+Tram C
+Plaine des sports|3|R|1760625480
+Universite|8|R|1760625780
+14:35:00
+.
+";
+        assert!(play(script).contains("|Plaine des sports  3|"));
+        let screen = play(&std::format!("{script}time 1760625600\n"));
+        assert!(screen.contains("|Universite         3|"), "{screen}");
+        assert!(!screen.contains("Plaine des sports"));
+    }
+
+    #[test]
+    fn clock_once_the_boot_is_over_and_at_night() {
+        let script = "message Connected to MQTT server !
+time 1760618280
+";
+        assert_eq!(
+            play(script),
+            "+--------------------+
+| ▀█  █▄█ · ══█ █═█  |
+| ▄█▄   █ · ▄▄█ █▄█  |
+| jeu. 16 oct. 2025  |
+|                    |
++--------------------+
+"
+        );
+
+        let script = std::format!("{script}publish next-tramway/line/C/1
+Tram C
+Gieres|3|R
+14:38:00
+.
+");
+        assert!(play(&script).contains("|Gieres             3|"));
+        let screen = play(&std::format!("{script}publish next-tramway/command/night\non\n.\n"));
+        assert!(screen.contains("jeu. 16 oct. 2025"), "{screen}");
+    }
+
+    #[test]
+    fn messages_are_wrapped() {
+        let screen = play("message Waiting to get IP address...\n");
+        assert_eq!(
+            screen,
+            "+--------------------+
+|Waiting to get IP ad|
+|dress...            |
+|                    |
+|                    |
++--------------------+
+"
+        );
+    }
+
+    #[test]
+    fn button_cycles_through_directions() {
+        let script = "publish next-tramway/line/A/1
+Tram A
+Fontaine|2|R
+14:35:10
+.
+publish next-tramway/line/A/2
+Tram A
+Grand'Place|5|R
+14:35:10
+.
+next
+";
+        assert!(play(script).contains("|Grand'Place        5|"));
+        assert!(play(&std::format!("{script}next\n")).contains("|Fontaine           2|"));
+    }
+
+    #[test]
+    fn small_lcd_only_shows_what_fits() {
+        let mut simulator = Simulator::new(LcdGeometry::L1602, LcdRom::A00, Staleness::default());
+        simulator.apply(Scenario::new("message Wifi connected !\n").next().unwrap().unwrap());
+        assert_eq!(
+            std::format!("{}", simulator.display),
+            "+----------------+
+|Wifi connected !|
+|                |
++----------------+
+"
+        );
+    }
+
+    const SMALL_SCRIPT: &str = "publish next-tramway/line/C/1
+Tram C
+Plaine des sports|3|R
+Universite - Condillac|8|S
+Le Prisme|12|R
+14:35:10
+.
+";
+
+    #[test]
+    fn small_lcd_pages_through_the_passages() {
+        assert_eq!(
+            play_on(LcdGeometry::L1602, SMALL_SCRIPT),
+            "+----------------+
+|Tram C Plaine  3|
+|Universite - * 8|
++----------------+
+"
+        );
+        assert!(play_on(LcdGeometry::L1602, &std::format!("{SMALL_SCRIPT}wait 5\n")).contains("|Le Prisme     12|"));
+        assert!(play_on(LcdGeometry::L1602, &std::format!("{SMALL_SCRIPT}wait 10\n")).contains("|MAJ     14:35:10|"));
+        assert!(play_on(LcdGeometry::L1602, &std::format!("{SMALL_SCRIPT}wait 15\n")).contains("|Universite - * 8|"));
+    }
+
+    #[test]
+    fn small_lcd_old_and_missing_data() {
+        assert_eq!(
+            play_on(LcdGeometry::L1602, &std::format!("{SMALL_SCRIPT}wait 160\n")),
+            "+----------------+
+|Tram C sports  1|
+|ANCIEN  14:35:10|
++----------------+
+"
+        );
+        assert_eq!(
+            play_on(LcdGeometry::L1602, &std::format!("{SMALL_SCRIPT}wait 600\n")),
+            "+----------------+
+|Tram C          |
+|Pas de donnees  |
++----------------+
+"
+        );
+        assert_eq!(
+            play_on(LcdGeometry::L1602, "publish next-tramway/line/A/1\nTram A\n14:35:10\n.\n"),
+            "+----------------+
+|Tram A          |
+|Pas de passage  |
++----------------+
+"
+        );
+    }
+
+    #[test]
+    fn accented_destinations() {
+        let screen = play(
+            "publish next-tramway/line/B/1
+Tram B
+Gières - Plaine des sports|2|R
+Université - Biologie|6|R
+14:35:10
+.
+",
+        );
+        assert!(screen.contains("|Gières - Plaine d  2|"));
+        assert!(screen.contains("|Université - Biol  6|"));
+
+        // more accented letters than CGRAM slots, the last ones lose their accent
+        let screen = play("message àâçéèêëîïôûù\n");
+        assert!(screen.contains("|àâçéèêëîiouu        |"));
+    }
+
+    #[test]
+    fn reports_script_errors() {
+        let errors: std::vec::Vec<_> = Scenario::new("dance\nwait soon\npublish next-tramway/line/C/1\nTram C\n")
+            .filter_map(|step| step.err())
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                ScenarioError { line: 1, kind: ScenarioErrorKind::UnknownStep },
+                ScenarioError { line: 2, kind: ScenarioErrorKind::InvalidNumber },
+                ScenarioError { line: 3, kind: ScenarioErrorKind::UnterminatedPayload },
+            ]
+        );
+
+        let mut scenario = Scenario::new("publish next-tramway/line/C/1\nTram C\n.\n");
+        assert!(matches!(
+            scenario.next(),
+            Some(Err(ScenarioError { line: 1, kind: ScenarioErrorKind::Parse(ParseError::MissingUpdateAt) }))
+        ));
+    }
+}
@@ -0,0 +1,129 @@
+// SNTP (RFC 4330) packets, the UDP exchange itself is done by the firmware with embassy-net
+// The device has no clock to put in the request: a random nonce goes in its transmit timestamp instead, the server
+// copies it in the originate timestamp of its response, which tells our response apart from a late or spoofed one
+
+use embassy_time::Duration;
+
+pub const NTP_PORT: u16 = 123;
+pub const PACKET_SIZE: usize = 48;
+
+// seconds between 1900-01-01 (NTP epoch) and 1970-01-01 (unix epoch)
+const NTP_TO_UNIX_SECS: u64 = 2_208_988_800;
+
+const LEAP_UNSYNCHRONIZED: u8 = 3;
+const VERSION: u8 = 4;
+const MODE_CLIENT: u8 = 3;
+const MODE_SERVER: u8 = 4;
+
+const ORIGINATE_OFFSET: usize = 24;
+const TRANSMIT_OFFSET: usize = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SntpError {
+    TooShort,
+    NotAServerResponse,
+    KissOfDeath, // stratum 0, the server asks us to go away or to slow down
+    Unsynchronized, // the server doesn't know the time itself
+    UnexpectedOriginate, // not the response to our request
+}
+
+pub fn request(nonce: u64) -> [u8; PACKET_SIZE] {
+    let mut packet = [0; PACKET_SIZE];
+    packet[0] = (VERSION << 3) | MODE_CLIENT;
+    packet[TRANSMIT_OFFSET..TRANSMIT_OFFSET + 8].copy_from_slice(&nonce.to_be_bytes());
+    packet
+}
+
+// UTC time in unix milliseconds when the response was received, `round_trip` is the time between the request and the
+// response, half of it is taken as the travel time of the response
+pub fn parse_response(response: &[u8], nonce: u64, round_trip: Duration) -> Result<u64, SntpError> {
+    if response.len() < PACKET_SIZE {
+        return Err(SntpError::TooShort);
+    }
+    let leap = response[0] >> 6;
+    let mode = response[0] & 0b111;
+    let stratum = response[1];
+    if mode != MODE_SERVER {
+        return Err(SntpError::NotAServerResponse);
+    }
+    if stratum == 0 {
+        return Err(SntpError::KissOfDeath);
+    }
+    if leap == LEAP_UNSYNCHRONIZED {
+        return Err(SntpError::Unsynchronized);
+    }
+    if timestamp(response, ORIGINATE_OFFSET) != nonce {
+        return Err(SntpError::UnexpectedOriginate);
+    }
+
+    let transmit = timestamp(response, TRANSMIT_OFFSET);
+    if transmit == 0 {
+        return Err(SntpError::Unsynchronized);
+    }
+    let (secs, fraction) = (transmit >> 32, transmit & 0xFFFF_FFFF);
+    // the seconds wrap in 2036, a time before 1970 is the next NTP era
+    let unix_secs = if secs >= NTP_TO_UNIX_SECS { secs - NTP_TO_UNIX_SECS } else { secs + (1 << 32) - NTP_TO_UNIX_SECS };
+    let millis = (fraction * 1000) >> 32;
+    Ok(unix_secs * 1000 + millis + round_trip.as_millis() / 2)
+}
+
+fn timestamp(packet: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&packet[offset..offset + 8]);
+    u64::from_be_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // stand-in for the NTP server of the network, answers like chrony or ntpd with the given unix time
+    fn stand_in_server(request: &[u8], unix_millis: u64) -> [u8; PACKET_SIZE] {
+        assert_eq!(request[0] & 0b111, MODE_CLIENT);
+        let mut response = [0; PACKET_SIZE];
+        response[0] = (request[0] & 0b0011_1000) | MODE_SERVER;
+        response[1] = 2; // stratum: synchronized to a stratum 1 server
+        response[ORIGINATE_OFFSET..ORIGINATE_OFFSET + 8].copy_from_slice(&request[TRANSMIT_OFFSET..TRANSMIT_OFFSET + 8]);
+        let secs = (unix_millis / 1000 + NTP_TO_UNIX_SECS) & 0xFFFF_FFFF;
+        let fraction = ((unix_millis % 1000) << 32) / 1000;
+        response[TRANSMIT_OFFSET..TRANSMIT_OFFSET + 8].copy_from_slice(&(secs << 32 | fraction).to_be_bytes());
+        response
+    }
+
+    #[test]
+    fn reads_the_server_time() {
+        let nonce = 0x1234_5678_9abc_def0;
+        let request = request(nonce);
+        assert_eq!(request[0], 0x23);
+
+        let response = stand_in_server(&request, 1_760_618_280_250);
+        assert_eq!(parse_response(&response, nonce, Duration::from_millis(0)), Ok(1_760_618_280_250));
+        // 40 ms of round trip, the response took about 20 of them
+        assert_eq!(parse_response(&response, nonce, Duration::from_millis(40)), Ok(1_760_618_280_270));
+
+        // after the wrap of the NTP seconds, in 2036
+        let response = stand_in_server(&request, 2_085_978_496_000 + 60_000);
+        assert_eq!(parse_response(&response, nonce, Duration::from_millis(0)), Ok(2_085_978_496_000 + 60_000));
+    }
+
+    #[test]
+    fn rejects_unusable_responses() {
+        let nonce = 42;
+        let response = stand_in_server(&request(nonce), 1_760_618_280_000);
+        let parse = |response: &[u8]| parse_response(response, nonce, Duration::from_millis(0));
+
+        assert_eq!(parse(&response[..40]), Err(SntpError::TooShort));
+        assert_eq!(parse(&stand_in_server(&request(43), 1_760_618_280_000)), Err(SntpError::UnexpectedOriginate));
+        // our own request, looped back
+        let mut looped = request(nonce);
+        looped[ORIGINATE_OFFSET..ORIGINATE_OFFSET + 8].copy_from_slice(&nonce.to_be_bytes());
+        assert_eq!(parse(&looped), Err(SntpError::NotAServerResponse));
+
+        let mut kiss_of_death = response;
+        kiss_of_death[1] = 0;
+        assert_eq!(parse(&kiss_of_death), Err(SntpError::KissOfDeath));
+        let mut unsynchronized = response;
+        unsynchronized[0] |= LEAP_UNSYNCHRONIZED << 6;
+        assert_eq!(parse(&unsynchronized), Err(SntpError::Unsynchronized));
+    }
+}
@@ -1,7 +1,6 @@
 import requests
 import json
 from collections import defaultdict
-import unicodedata
 import time
 
 from appdaemon.plugins.hass import Hass
@@ -17,6 +16,7 @@ lines_display_name = { "A": "Tram A", "B": "Tram B", "C": "Tram C", "D": "Tram D
 STOP_ID = ""
 
 UPDATE_EVERY=20 # in seconds
+PASSAGES_PER_DIRECTION=2 # at most MAX_PASSAGES of the device, the 20x4 LCD shows 2 of them
 
 class NextTramway(Hass):
     def initialize(self):
@@ -51,7 +51,8 @@ class NextTramway(Hass):
             if any(stop_time["pattern"]["id"].startswith(line) for line in lines_to_keep):
                 print(json.dumps(stop_time, indent=2))
                 for stop in stop_time["times"]:
-                    relative_arrival = relative_minutes(stop["realtimeArrival"], now_sec_since_midnight())
+                    now_sec = now_sec_since_midnight()
+                    relative_arrival = relative_minutes(stop["realtimeArrival"], now_sec)
                     if relative_arrival < 0:
                         print(f"Skipping stop with arrival time in the past: {stop['realtimeArrival']} (relative: {relative_arrival} minutes)")
                         continue
@@ -63,6 +64,8 @@ class NextTramway(Hass):
                         "dir": direction,
                         #"arrival_time": sec_to_hms(stop["realtimeArrival"]),
                         "relative_arrival_time": relative_arrival,
+                        # the device counts down from it once it knows the time, unaffected by the MQTT delays
+                        "arrival_epoch": int(time.time()) + (stop["realtimeArrival"] - now_sec) % 86400,
                         "destination_short": stop_time["pattern"]["desc"],
                         #"destination_long": stop_time["pattern"]["lastStopName"],
                         "realtime_state": stop["realtimeState"]
@@ -92,13 +95,12 @@ class NextTramway(Hass):
                     retain=True
                 )
             
-        def strip_accents(s: str) -> str:
-            return ''.join(
-                c for c in unicodedata.normalize('NFD', s)
-                if unicodedata.category(c) != 'Mn'
-            )
+        # the accents are kept, the display maps them to what it can show
         def sanitize(s):
-            return strip_accents(s.replace("|", " ").replace("\n", " "))
+            return s.replace("|", " ").replace("\n", " ")
+        # the display scrolls the long destinations, but they must fit in its 32 bytes buffer
+        def fit_destination(s):
+            return s.encode()[:32].decode(errors="ignore")
 
         for line in lines_to_show:
             gen_empty_line(stops_by_line_by_dir, line)
@@ -109,11 +111,12 @@ class NextTramway(Hass):
                 timestamp = time.strftime("%H:%M:%S")
 
                 passages = [
-                    f"{sanitize(stop['destination_short'])[:17]}|"
+                    f"{fit_destination(sanitize(stop['destination_short']))}|"
                     f"{min(stop['relative_arrival_time'], 60)}|"
-                    f"{'R' if stop['realtime_state'] == 'UPDATED' else 'S'}"
+                    f"{'R' if stop['realtime_state'] == 'UPDATED' else 'S'}|"
+                    f"{stop['arrival_epoch']}"
                     for stop in stops_by_dir
-                ][:2]
+                ][:PASSAGES_PER_DIRECTION]
 
                 payload = "\n".join([display_name, *passages, timestamp])
 